@@ -1,15 +1,23 @@
 use bytes::Bytes;
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 
-fn parse(line: &Bytes) -> Option<statsdproto::PDU> {
+fn parse(line: &[u8]) -> Result<statsdproto::PduRef<'_>, statsdproto::ParseError> {
+    statsdproto::PduRef::new(line)
+}
+
+fn parse_owned(line: &Bytes) -> Result<statsdproto::PDU, statsdproto::ParseError> {
     statsdproto::PDU::new(line.clone())
 }
 
 fn criterion_benchmark(c: &mut Criterion) {
-    let by = Bytes::from_static(
-        b"hello_world.worldworld_i_am_a_pumpkin:3|c|@1.0|#tags:tags,tags:tags,tags:tags,tags:tags",
-    );
-    c.bench_function("statsd pdu parsing", |b| b.iter(|| parse(black_box(&by))));
+    let line: &[u8] =
+        b"hello_world.worldworld_i_am_a_pumpkin:3|c|@1.0|#tags:tags,tags:tags,tags:tags,tags:tags";
+    c.bench_function("statsd pdu parsing", |b| b.iter(|| parse(black_box(line))));
+
+    let by = Bytes::from_static(line);
+    c.bench_function("statsd owned pdu parsing", |b| {
+        b.iter(|| parse_owned(black_box(&by)))
+    });
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -0,0 +1,507 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::cardinality::{valid_precision, CardinalitySet};
+use crate::key::MetricKey;
+use crate::metric::{Metric, MetricError, MetricType, MetricValue};
+use crate::pdu::PDU;
+use crate::sketch::{validate_accuracy, DDSketch};
+use crate::stats::TimerStats;
+
+/// The identity of a series, now `MetricKey`.
+#[deprecated(note = "use `MetricKey`, which `SeriesKey` is an alias of")]
+pub type SeriesKey = MetricKey;
+
+/// The samples collected for a timer over one flush interval, along with the
+/// number of samples they stand for once upscaled by their sample rates.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TimerSamples {
+    samples: Vec<f64>,
+    count: f64,
+}
+
+impl TimerSamples {
+    pub fn samples(&self) -> &[f64] {
+        &self.samples
+    }
+
+    /// The number of samples sent by clients, accounting for sampling.
+    pub fn count(&self) -> f64 {
+        self.count
+    }
+
+    /// Compute the Etsy statsd statistics of the timer, reporting each of
+    /// the given percentile thresholds.
+    pub fn stats(&self, thresholds: &[f64], flush_interval: Duration) -> Option<TimerStats> {
+        TimerStats::from_samples(&self.samples, self.count, thresholds, flush_interval)
+    }
+
+    fn add(&mut self, value: f64, scale: f64) {
+        self.samples.push(value);
+        self.count += scale;
+    }
+}
+
+/// An Aggregator keeps per-series state for metrics over a flush interval,
+/// following the semantics of Etsy statsd:
+///
+/// - Counters are summed, upscaled by their sample rate.
+/// - Gauges keep their last value, are adjusted by signed deltas, and carry
+///   over from one flush interval to the next. Every gauge ever seen is kept
+///   and reported unless the aggregator is built `with_gauge_expiry`.
+/// - Timers, histograms and distributions collect their samples, or are
+///   summarized in a `DDSketch` when the aggregator is built `with_sketches`.
+///   The three types are reported with the same statistics, so by design a
+///   `ms`, `h` and `d` metric of the same name and tags share one series,
+///   keyed by `MetricClass::Timer`.
+/// - Sets count their distinct members, exactly up to a limit and then
+///   approximately in a `HyperLogLog`.
+///
+/// Metrics of unknown type are ignored.
+#[derive(Debug, Clone)]
+pub struct Aggregator {
+    counters: HashMap<MetricKey, f64>,
+    gauges: HashMap<MetricKey, GaugeState>,
+    timers: HashMap<MetricKey, TimerSamples>,
+    sketches: HashMap<MetricKey, DDSketch>,
+    sets: HashMap<MetricKey, CardinalitySet>,
+    gauge_expiry: Option<usize>,
+    sketch_accuracy: Option<f64>,
+    set_exact_limit: usize,
+    set_precision: u8,
+}
+
+impl Default for Aggregator {
+    fn default() -> Self {
+        Aggregator {
+            counters: HashMap::new(),
+            gauges: HashMap::new(),
+            timers: HashMap::new(),
+            sketches: HashMap::new(),
+            sets: HashMap::new(),
+            gauge_expiry: None,
+            sketch_accuracy: None,
+            set_exact_limit: CardinalitySet::DEFAULT_EXACT_LIMIT,
+            set_precision: CardinalitySet::DEFAULT_PRECISION,
+        }
+    }
+}
+
+impl Aggregator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drop gauges which have gone more than `idle_flushes` flush intervals
+    /// without an update, rather than reporting them forever. With `0`,
+    /// gauges are only reported for the intervals in which they were updated,
+    /// like the `deleteGauges` option of Etsy statsd.
+    pub fn with_gauge_expiry(mut self, idle_flushes: usize) -> Self {
+        self.gauge_expiry = Some(idle_flushes);
+        self
+    }
+
+    /// Summarize timers, histograms and distributions in sketches with the
+    /// given relative accuracy rather than keeping every sample, trading
+    /// exact statistics for memory bounded by the range of the values.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the relative accuracy is not between 0 and 1, exclusive.
+    pub fn with_sketches(mut self, relative_accuracy: f64) -> Self {
+        // Validate up front rather than on the first sample
+        validate_accuracy(relative_accuracy).expect("relative accuracy must be between 0 and 1");
+        self.sketch_accuracy = Some(relative_accuracy);
+        self
+    }
+
+    /// Count the members of each set exactly up to `exact_limit`, then in a
+    /// `HyperLogLog` of the given precision.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the precision is not valid for a `HyperLogLog`.
+    pub fn with_set_limits(mut self, exact_limit: usize, precision: u8) -> Self {
+        assert!(
+            valid_precision(precision),
+            "precision must be between 4 and 18"
+        );
+        self.set_exact_limit = exact_limit;
+        self.set_precision = precision;
+        self
+    }
+
+    /// Interpret a PDU as a typed metric and add it. Timers, histograms and
+    /// distributions packing several values into one line, as parsed by
+    /// `PDU::new_multi_value`, add every value, or none if any is invalid.
+    pub fn add_pdu(&mut self, pdu: &PDU) -> Result<(), MetricError> {
+        match MetricType::from_bytes(pdu.pdu_type()) {
+            MetricType::Timer | MetricType::Histogram | MetricType::Distribution => {
+                let metrics = pdu
+                    .values()
+                    .map(|value| Metric::from_pdu_value(pdu, value))
+                    .collect::<Result<Vec<_>, _>>()?;
+                for metric in &metrics {
+                    self.add(metric);
+                }
+            }
+            _ => self.add(&Metric::from_pdu(pdu)?),
+        }
+        Ok(())
+    }
+
+    pub fn add(&mut self, metric: &Metric) {
+        let key = MetricKey::from_metric(metric);
+        // Metrics only carry finite, positive sample rates
+        let scale = metric.sample_rate().map_or(1.0, |rate| 1.0 / rate);
+        match metric.value() {
+            MetricValue::Counter(v) => *series(&mut self.counters, key) += v * scale,
+            MetricValue::Gauge(v) => series(&mut self.gauges, key).set(*v),
+            MetricValue::GaugeDelta(v) => {
+                let gauge = series(&mut self.gauges, key);
+                gauge.set(gauge.value + v)
+            }
+            MetricValue::Timer(v) | MetricValue::Histogram(v) | MetricValue::Distribution(v) => {
+                match self.sketch_accuracy {
+                    Some(accuracy) => {
+                        series_with(&mut self.sketches, key, || DDSketch::new(accuracy))
+                            .add_weighted(*v, scale)
+                    }
+                    None => series(&mut self.timers, key).add(*v, scale),
+                }
+            }
+            MetricValue::Set(member) => {
+                let (exact_limit, precision) = (self.set_exact_limit, self.set_precision);
+                series_with(&mut self.sets, key, || {
+                    CardinalitySet::new(exact_limit, precision)
+                })
+                .insert(member)
+            }
+            MetricValue::Unknown { .. } => (),
+        }
+    }
+
+    /// Take a snapshot of the current flush interval and start the next one.
+    /// Gauges keep their values, while every other series is reset. Without
+    /// a gauge expiry the gauges, and the snapshot of them, grow with every
+    /// new gauge series seen.
+    pub fn flush(&mut self) -> Snapshot {
+        if let Some(idle_flushes) = self.gauge_expiry {
+            self.gauges
+                .retain(|_, gauge| gauge.idle_flushes <= idle_flushes);
+        }
+        let gauges = self
+            .gauges
+            .iter_mut()
+            .map(|(key, gauge)| {
+                gauge.idle_flushes += 1;
+                (key.clone(), gauge.value)
+            })
+            .collect();
+        Snapshot::new(
+            std::mem::take(&mut self.counters),
+            gauges,
+            std::mem::take(&mut self.timers),
+            std::mem::take(&mut self.sketches),
+            std::mem::take(&mut self.sets),
+        )
+    }
+}
+
+/// The value of a gauge, along with the number of flushes since it was last
+/// updated.
+#[derive(Debug, Clone, Default)]
+struct GaugeState {
+    value: f64,
+    idle_flushes: usize,
+}
+
+impl GaugeState {
+    fn set(&mut self, value: f64) {
+        self.value = value;
+        self.idle_flushes = 0;
+    }
+}
+
+/// Look up the state of a series, detaching the key when it is first added.
+fn series<V: Default>(map: &mut HashMap<MetricKey, V>, key: MetricKey) -> &mut V {
+    series_with(map, key, V::default)
+}
+
+fn series_with<V>(
+    map: &mut HashMap<MetricKey, V>,
+    key: MetricKey,
+    init: impl FnOnce() -> V,
+) -> &mut V {
+    if !map.contains_key(&key) {
+        map.insert(key.detach(), init());
+    }
+    map.get_mut(&key).unwrap()
+}
+
+/// The aggregated state of every series over one flush interval.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    counters: HashMap<MetricKey, f64>,
+    gauges: HashMap<MetricKey, f64>,
+    timers: HashMap<MetricKey, TimerSamples>,
+    sketches: HashMap<MetricKey, DDSketch>,
+    sets: HashMap<MetricKey, usize>,
+    set_cardinalities: HashMap<MetricKey, CardinalitySet>,
+}
+
+impl Snapshot {
+    fn new(
+        counters: HashMap<MetricKey, f64>,
+        gauges: HashMap<MetricKey, f64>,
+        timers: HashMap<MetricKey, TimerSamples>,
+        sketches: HashMap<MetricKey, DDSketch>,
+        set_cardinalities: HashMap<MetricKey, CardinalitySet>,
+    ) -> Self {
+        let sets = set_cardinalities
+            .iter()
+            .map(|(key, set)| (key.clone(), set.cardinality().estimate.round() as usize))
+            .collect();
+        Snapshot {
+            counters,
+            gauges,
+            timers,
+            sketches,
+            sets,
+            set_cardinalities,
+        }
+    }
+
+    /// The upscaled sum of each counter.
+    pub fn counters(&self) -> &HashMap<MetricKey, f64> {
+        &self.counters
+    }
+
+    pub fn gauges(&self) -> &HashMap<MetricKey, f64> {
+        &self.gauges
+    }
+
+    pub fn timers(&self) -> &HashMap<MetricKey, TimerSamples> {
+        &self.timers
+    }
+
+    /// The sketches of timers, histograms and distributions, when the
+    /// aggregator was built `with_sketches`. Each is weighted by the sample
+    /// rates of its values.
+    pub fn sketches(&self) -> &HashMap<MetricKey, DDSketch> {
+        &self.sketches
+    }
+
+    /// The number of distinct members of each set, rounded when the set is
+    /// approximate. See `set_cardinalities` for the error bounds.
+    pub fn sets(&self) -> &HashMap<MetricKey, usize> {
+        &self.sets
+    }
+
+    /// The distinct members of each set, whose `cardinality` is the count
+    /// along with its error bounds. Sets can be merged across aggregators.
+    pub fn set_cardinalities(&self) -> &HashMap<MetricKey, CardinalitySet> {
+        &self.set_cardinalities
+    }
+}
+
+#[cfg(test)]
+pub mod atest {
+    use super::*;
+    use bytes::Bytes;
+
+    fn aggregate(lines: &[&'static [u8]]) -> Aggregator {
+        let mut aggregator = Aggregator::new();
+        for line in lines {
+            let pdu = PDU::new(Bytes::from_static(line)).unwrap();
+            aggregator.add_pdu(&pdu).unwrap();
+        }
+        aggregator
+    }
+
+    fn key(snapshot: &HashMap<MetricKey, impl Sized>, name: &[u8]) -> MetricKey {
+        snapshot.keys().find(|k| k.name() == name).unwrap().clone()
+    }
+
+    #[test]
+    fn aggregate_counters() {
+        let snapshot = aggregate(&[
+            b"hits:1|c|#b:2,a:1",
+            b"hits:2|c|@0.5|#a:1,b:2,a:1",
+            b"hits:1|c|#a:1",
+        ])
+        .flush();
+        let mut counters: Vec<(usize, f64)> = snapshot
+            .counters()
+            .iter()
+            .map(|(key, value)| (key.tags().len(), *value))
+            .collect();
+        counters.sort_by_key(|(tags, _)| *tags);
+        assert_eq!(counters, vec![(1, 1.0), (2, 5.0)]);
+    }
+
+    #[test]
+    fn aggregate_gauges() {
+        let mut aggregator = aggregate(&[b"temp:10|g", b"temp:+5|g", b"temp:-3|g", b"new:-2|g"]);
+        let snapshot = aggregator.flush();
+        assert_eq!(snapshot.gauges()[&key(snapshot.gauges(), b"temp")], 12.0);
+        assert_eq!(snapshot.gauges()[&key(snapshot.gauges(), b"new")], -2.0);
+
+        // Gauges carry over to the next interval
+        aggregator
+            .add_pdu(&PDU::new(Bytes::from_static(b"temp:+1|g")).unwrap())
+            .unwrap();
+        let snapshot = aggregator.flush();
+        assert_eq!(snapshot.gauges()[&key(snapshot.gauges(), b"temp")], 13.0);
+        assert_eq!(snapshot.gauges().len(), 2);
+    }
+
+    #[test]
+    fn expire_gauges() {
+        let pdu = |line: &'static [u8]| PDU::new(Bytes::from_static(line)).unwrap();
+        let mut aggregator = Aggregator::new().with_gauge_expiry(1);
+        aggregator.add_pdu(&pdu(b"temp:10|g")).unwrap();
+        aggregator.add_pdu(&pdu(b"load:1|g")).unwrap();
+        assert_eq!(aggregator.flush().gauges().len(), 2);
+
+        // Updated gauges are kept, idle ones are reported once more
+        aggregator.add_pdu(&pdu(b"temp:+1|g")).unwrap();
+        assert_eq!(aggregator.flush().gauges().len(), 2);
+        let snapshot = aggregator.flush();
+        assert_eq!(snapshot.gauges().len(), 1);
+        assert_eq!(snapshot.gauges()[&key(snapshot.gauges(), b"temp")], 11.0);
+        assert!(aggregator.flush().gauges().is_empty());
+
+        let mut aggregator = Aggregator::new().with_gauge_expiry(0);
+        aggregator.add_pdu(&pdu(b"temp:10|g")).unwrap();
+        assert_eq!(aggregator.flush().gauges().len(), 1);
+        assert!(aggregator.flush().gauges().is_empty());
+        // An expired gauge starts over from zero, as a new gauge
+        aggregator.add_pdu(&pdu(b"temp:+2|g")).unwrap();
+        let snapshot = aggregator.flush();
+        assert_eq!(snapshot.gauges()[&key(snapshot.gauges(), b"temp")], 2.0);
+    }
+
+    #[test]
+    fn timer_types_share_a_series() {
+        let snapshot = aggregate(&[b"lat:10|ms", b"lat:20|h", b"lat:30|d"]).flush();
+        assert_eq!(snapshot.timers().len(), 1);
+        let lat = &snapshot.timers()[&key(snapshot.timers(), b"lat")];
+        assert_eq!(lat.samples(), &[10.0, 20.0, 30.0]);
+    }
+
+    #[test]
+    fn aggregate_timers_and_sets() {
+        let mut aggregator = aggregate(&[
+            b"lat:10|ms",
+            b"lat:30|ms|@0.25",
+            b"size:4|h",
+            b"users:a|s",
+            b"users:b|s",
+            b"users:a|s",
+            b"other:1|kv",
+        ]);
+        let snapshot = aggregator.flush();
+        let lat = &snapshot.timers()[&key(snapshot.timers(), b"lat")];
+        assert_eq!(lat.samples(), &[10.0, 30.0]);
+        assert_eq!(lat.count(), 5.0);
+        let stats = lat.stats(&[90.0], Duration::from_secs(10)).unwrap();
+        assert_eq!(stats.count_ps, 0.5);
+        assert_eq!(stats.mean, 20.0);
+        assert_eq!(snapshot.timers().len(), 2);
+        let users = key(snapshot.sets(), b"users");
+        assert_eq!(snapshot.sets()[&users], 2);
+        assert!(snapshot.set_cardinalities()[&users].cardinality().exact);
+
+        let snapshot = aggregator.flush();
+        assert!(snapshot.counters().is_empty());
+        assert!(snapshot.timers().is_empty());
+        assert!(snapshot.sets().is_empty());
+        assert!(snapshot.set_cardinalities().is_empty());
+    }
+
+    #[test]
+    fn aggregate_packed_values() {
+        let mut aggregator = Aggregator::new();
+        for line in &[&b"lat:10:20:30|ms|@0.5"[..], b"size:1.5:2|d", b"lat:40|ms"] {
+            aggregator
+                .add_pdu(&PDU::new_multi_value(Bytes::from_static(line)).unwrap())
+                .unwrap();
+        }
+        let invalid = PDU::new_multi_value(Bytes::from_static(b"lat:50:x|ms")).unwrap();
+        assert_eq!(aggregator.add_pdu(&invalid), Err(MetricError::InvalidValue));
+        let snapshot = aggregator.flush();
+        let lat = &snapshot.timers()[&key(snapshot.timers(), b"lat")];
+        assert_eq!(lat.samples(), &[10.0, 20.0, 30.0, 40.0]);
+        assert_eq!(lat.count(), 7.0);
+        let size = &snapshot.timers()[&key(snapshot.timers(), b"size")];
+        assert_eq!(size.samples(), &[1.5, 2.0]);
+
+        let mut aggregator = Aggregator::new().with_sketches(0.01);
+        aggregator
+            .add_pdu(&PDU::new_multi_value(Bytes::from_static(b"lat:1:2:3|d")).unwrap())
+            .unwrap();
+        let snapshot = aggregator.flush();
+        assert_eq!(
+            snapshot.sketches()[&key(snapshot.sketches(), b"lat")].count(),
+            3.0
+        );
+    }
+
+    #[test]
+    fn aggregate_sketches() {
+        let mut aggregator = Aggregator::new().with_sketches(0.01);
+        for line in &[&b"lat:10|ms"[..], b"lat:30|ms|@0.25", b"size:100|d"] {
+            aggregator
+                .add_pdu(&PDU::new(Bytes::from_static(line)).unwrap())
+                .unwrap();
+        }
+        let snapshot = aggregator.flush();
+        assert!(snapshot.timers().is_empty());
+        let lat = &snapshot.sketches()[&key(snapshot.sketches(), b"lat")];
+        assert_eq!(lat.count(), 5.0);
+        assert_eq!(lat.sum(), 130.0);
+        assert_eq!(lat.quantile(1.0), Some(30.0));
+        assert_eq!(snapshot.sketches().len(), 2);
+        assert!(aggregator.flush().sketches().is_empty());
+    }
+
+    #[test]
+    fn aggregate_large_sets() {
+        let mut aggregator = Aggregator::new().with_set_limits(10, 12);
+        for i in 0..1000 {
+            let line = format!("users:{}|s", i);
+            aggregator
+                .add_pdu(&PDU::new(Bytes::from(line)).unwrap())
+                .unwrap();
+        }
+        let snapshot = aggregator.flush();
+        let key = key(snapshot.sets(), b"users");
+        let users = &snapshot.set_cardinalities()[&key];
+        assert!(!users.is_exact());
+        let cardinality = users.cardinality();
+        assert!(cardinality.lower <= 1000.0 && 1000.0 <= cardinality.upper);
+        assert_eq!(snapshot.sets()[&key], cardinality.estimate.round() as usize);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn series_key_alias() {
+        let snapshot = aggregate(&[b"hits:1|c|#a:1"]).flush();
+        let key: &SeriesKey = snapshot.counters().keys().next().unwrap();
+        assert_eq!(key.name(), b"hits");
+        assert_eq!(key.tags().len(), 1);
+    }
+
+    #[test]
+    fn aggregated_keys_are_detached() {
+        let buf = Bytes::from(b"foo:1|c|#a:b".to_vec());
+        let mut aggregator = Aggregator::new();
+        aggregator.add_pdu(&PDU::new(buf.clone()).unwrap()).unwrap();
+        let snapshot = aggregator.flush();
+        let key = key(snapshot.counters(), b"foo");
+        let range = buf.as_ptr() as usize..buf.as_ptr() as usize + buf.len();
+        assert!(!range.contains(&(key.name().as_ptr() as usize)));
+        assert!(!range.contains(&(key.tags()[0].0.as_ptr() as usize)));
+    }
+}
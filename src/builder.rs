@@ -0,0 +1,479 @@
+use std::fmt;
+
+use crate::metric::MetricType;
+use crate::pdu::{Parts, Section, PDU};
+use crate::tags::Tag;
+
+/// The reason a `PduBuilder` could not produce a protocol unit, or a message
+/// such as a `ServiceCheck` could not be encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum BuildError {
+    /// No value was set.
+    MissingValue,
+    /// No type was set.
+    MissingType,
+    /// The name was empty or contained a reserved byte, including `:`.
+    InvalidName,
+    /// The value was not finite or contained a reserved byte. `:` is only
+    /// allowed in multi-value mode.
+    InvalidValue,
+    /// The type was empty or contained a reserved byte.
+    InvalidType,
+    /// The sample rate was not a finite, positive number.
+    InvalidSampleRate,
+    /// A tag key was empty, or a tag contained a reserved byte.
+    InvalidTag,
+    /// An extension was too short, contained a reserved byte or would be
+    /// mistaken for a known section.
+    InvalidExtension,
+    /// The hostname contained a reserved byte.
+    InvalidHostname,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            BuildError::MissingValue => "missing value",
+            BuildError::MissingType => "missing type",
+            BuildError::InvalidName => "invalid name",
+            BuildError::InvalidValue => "invalid value",
+            BuildError::InvalidType => "invalid type",
+            BuildError::InvalidSampleRate => "invalid sample rate",
+            BuildError::InvalidTag => "invalid tag",
+            BuildError::InvalidExtension => "invalid extension",
+            BuildError::InvalidHostname => "invalid hostname",
+        };
+        f.write_str(description)
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// A PduBuilder constructs a protocol unit from its parts, writing a single
+/// buffer with the field offsets already set, so no parsing is needed.
+///
+/// A signed gauge value, such as `g:-5|g`, is read as a delta to the current
+/// value of the gauge, so `build` rejects negative gauge values other than
+/// those set with `with_gauge_delta`. To set a gauge to an absolute negative
+/// value, send `g:0|g` first, followed by the negative delta.
+#[derive(Debug, Clone, Default)]
+pub struct PduBuilder {
+    name: Vec<u8>,
+    value: Option<Vec<u8>>,
+    pdu_type: Option<Vec<u8>>,
+    sample_rate: Option<f64>,
+    tags: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    timestamp: Option<u64>,
+    extensions: Vec<Vec<u8>>,
+    invalid_value: bool,
+    multi_value: bool,
+    gauge_delta: bool,
+}
+
+impl PduBuilder {
+    pub fn new(name: impl AsRef<[u8]>) -> Self {
+        PduBuilder {
+            name: name.as_ref().to_vec(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_integer(mut self, value: i64) -> Self {
+        self.invalid_value = false;
+        self.gauge_delta = false;
+        self.value = Some(itoa::Buffer::new().format(value).as_bytes().to_vec());
+        self
+    }
+
+    /// Set a floating point value, written in its shortest form which parses
+    /// back to the same number. Integral values are written without a
+    /// fraction, so `3.0` is written as `3`.
+    pub fn with_float(mut self, value: f64) -> Self {
+        self.invalid_value = !value.is_finite();
+        self.gauge_delta = false;
+        self.value = Some(format_float(value));
+        self
+    }
+
+    /// Set the value as raw bytes, such as a set member or several packed
+    /// multi-value values.
+    pub fn with_raw_value(mut self, value: impl AsRef<[u8]>) -> Self {
+        self.invalid_value = false;
+        self.gauge_delta = false;
+        self.value = Some(value.as_ref().to_vec());
+        self
+    }
+
+    /// Make the protocol unit a gauge adjusting the current value of the
+    /// gauge by `delta`, written with an explicit sign such as `+5` or `-5`.
+    pub fn with_gauge_delta(mut self, delta: f64) -> Self {
+        self.invalid_value = !delta.is_finite();
+        self.gauge_delta = true;
+        let mut value = format_float(delta);
+        if !delta.is_sign_negative() {
+            value.insert(0, b'+');
+        }
+        self.value = Some(value);
+        self.with_metric_type(MetricType::Gauge)
+    }
+
+    /// Allow raw values to pack several `:` separated values, as parsed by
+    /// `PDU::new_multi_value`.
+    pub fn with_multi_value(mut self, multi_value: bool) -> Self {
+        self.multi_value = multi_value;
+        self
+    }
+
+    /// Set the type from raw bytes, such as `c` or a non-standard type.
+    pub fn with_type(mut self, pdu_type: impl AsRef<[u8]>) -> Self {
+        self.pdu_type = Some(pdu_type.as_ref().to_vec());
+        self
+    }
+
+    /// Set the type of a known metric. `MetricType::Unknown` clears the type.
+    pub fn with_metric_type(mut self, metric_type: MetricType) -> Self {
+        self.pdu_type = metric_type.as_bytes().map(<[u8]>::to_vec);
+        self
+    }
+
+    pub fn with_sample_rate(mut self, sample_rate: f64) -> Self {
+        self.sample_rate = Some(sample_rate);
+        self
+    }
+
+    /// Add a tag, which is written in DogStatsD form in the order added.
+    pub fn with_tag(mut self, key: impl AsRef<[u8]>, value: Option<impl AsRef<[u8]>>) -> Self {
+        self.tags
+            .push((key.as_ref().to_vec(), value.map(|v| v.as_ref().to_vec())));
+        self
+    }
+
+    /// Set the DogStatsD client side unix timestamp, in seconds.
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Add an extension section, without the leading `|`, written after all
+    /// known sections. Extensions must be at least two bytes long, as a
+    /// shorter trailing section is read back as part of the type.
+    pub fn with_extension(mut self, extension: impl AsRef<[u8]>) -> Self {
+        self.extensions.push(extension.as_ref().to_vec());
+        self
+    }
+
+    /// Write the protocol unit out to a new PDU.
+    pub fn build(&self) -> Result<PDU, BuildError> {
+        let value = self.value.as_deref().ok_or(BuildError::MissingValue)?;
+        let pdu_type = self.pdu_type.as_deref().ok_or(BuildError::MissingType)?;
+        check_name(&self.name)?;
+        if self.invalid_value {
+            return Err(BuildError::InvalidValue);
+        }
+        check_value(value, self.multi_value)?;
+        check_type(pdu_type)?;
+        if pdu_type == b"g" && value.starts_with(b"-") && !self.gauge_delta {
+            // This would be read back as a delta
+            return Err(BuildError::InvalidValue);
+        }
+        let sample_rate = match self.sample_rate {
+            Some(rate) if !rate.is_finite() || rate <= 0.0 => {
+                return Err(BuildError::InvalidSampleRate)
+            }
+            Some(rate) => Some(format_float(rate)),
+            None => None,
+        };
+        let mut tags = Vec::with_capacity(self.tags.len());
+        for (key, value) in &self.tags {
+            let tag = Tag::new(key, value.as_deref());
+            check_tag(&tag)?;
+            tags.push(tag);
+        }
+        let mut extensions = Vec::with_capacity(self.extensions.len());
+        for extension in &self.extensions {
+            if extension.len() < 2
+                || has_reserved(extension, b"|\n")
+                || Section::classify(extension).is_some()
+            {
+                return Err(BuildError::InvalidExtension);
+            }
+            extensions.push(extension.as_slice());
+        }
+        let timestamp = self
+            .timestamp
+            .map(|timestamp| itoa::Buffer::new().format(timestamp).as_bytes().to_vec());
+
+        Ok(Parts {
+            name: &self.name,
+            value,
+            pdu_type,
+            sample_rate: sample_rate.as_deref(),
+            tags,
+            timestamp: timestamp.as_deref(),
+            container_id: None,
+            cardinality: None,
+            extensions,
+        }
+        .build())
+    }
+}
+
+/// Format a float in its shortest round-tripping form, dropping the fraction
+/// of integral values.
+fn format_float(value: f64) -> Vec<u8> {
+    let mut buffer = ryu::Buffer::new();
+    let formatted = buffer.format(value).as_bytes();
+    formatted.strip_suffix(b".0").unwrap_or(formatted).to_vec()
+}
+
+/// Check a name can be written such that parsing finds the same name: it
+/// must not contain `:`, which would be read as the start of the value.
+pub(crate) fn check_name(name: &[u8]) -> Result<(), BuildError> {
+    if name.is_empty() || has_reserved(name, b":|\n") {
+        return Err(BuildError::InvalidName);
+    }
+    Ok(())
+}
+
+pub(crate) fn check_value(value: &[u8], multi_value: bool) -> Result<(), BuildError> {
+    let reserved: &[u8] = if multi_value { b"|\n" } else { b":|\n" };
+    if value.is_empty() || has_reserved(value, reserved) {
+        return Err(BuildError::InvalidValue);
+    }
+    Ok(())
+}
+
+pub(crate) fn check_type(pdu_type: &[u8]) -> Result<(), BuildError> {
+    if pdu_type.is_empty() || has_reserved(pdu_type, b"|\n") {
+        return Err(BuildError::InvalidType);
+    }
+    Ok(())
+}
+
+/// Check a tag can be written in DogStatsD form and parse back the same: the
+/// key must be non-empty without `:` or `,`, and the value without `,`.
+pub(crate) fn check_tag(tag: &Tag<'_>) -> Result<(), BuildError> {
+    if tag.key.is_empty()
+        || has_reserved(tag.key, b":,|\n")
+        || matches!(tag.value, Some(v) if has_reserved(v, b",|\n"))
+    {
+        return Err(BuildError::InvalidTag);
+    }
+    Ok(())
+}
+
+/// Check a raw sample rate is a finite, positive number.
+pub(crate) fn check_sample_rate(sample_rate: &[u8]) -> Result<(), BuildError> {
+    let rate = std::str::from_utf8(sample_rate)
+        .ok()
+        .and_then(|rate| rate.parse::<f64>().ok());
+    match rate {
+        Some(rate) if rate.is_finite() && rate > 0.0 => Ok(()),
+        _ => Err(BuildError::InvalidSampleRate),
+    }
+}
+
+pub(crate) fn has_reserved(field: &[u8], reserved: &[u8]) -> bool {
+    field.iter().any(|b| reserved.contains(b))
+}
+
+#[cfg(test)]
+pub mod atest {
+    use super::*;
+    use crate::metric::{Metric, MetricValue};
+
+    #[test]
+    fn build_pdu() {
+        let pdu = PduBuilder::new("foo.bar")
+            .with_integer(-3)
+            .with_metric_type(MetricType::Counter)
+            .with_sample_rate(0.5)
+            .with_tag("env", Some("prod"))
+            .with_tag("canary", None::<&[u8]>)
+            .with_timestamp(1656581400)
+            .with_extension("x:abc")
+            .build()
+            .unwrap();
+        assert_eq!(
+            pdu.as_ref(),
+            b"foo.bar:-3|c|@0.5|#env:prod,canary|T1656581400|x:abc"
+        );
+        assert_eq!(pdu.name(), b"foo.bar");
+        assert_eq!(pdu.value(), b"-3");
+        assert_eq!(pdu.sample_rate().unwrap(), b"0.5");
+        assert_eq!(pdu.tags().unwrap(), b"env:prod,canary");
+        assert_eq!(pdu.timestamp().unwrap(), b"1656581400");
+        assert_eq!(pdu.extensions().collect::<Vec<_>>(), vec![b"x:abc"]);
+
+        // The built buffer parses back to the same offsets
+        let parsed = PDU::new_lenient(pdu.as_ref().to_vec().into()).unwrap();
+        assert_eq!(parsed, pdu);
+        assert_eq!(parsed.tags(), pdu.tags());
+        assert_eq!(parsed.timestamp(), pdu.timestamp());
+    }
+
+    #[test]
+    fn build_values() {
+        let build = |builder: PduBuilder| builder.with_type("ms").build().unwrap();
+        assert_eq!(build(PduBuilder::new("a").with_float(3.0)).value(), b"3");
+        assert_eq!(build(PduBuilder::new("a").with_float(0.1)).value(), b"0.1");
+        assert_eq!(
+            build(PduBuilder::new("a").with_float(-2.5e-7)).value(),
+            b"-2.5e-7"
+        );
+        assert_eq!(
+            build(PduBuilder::new("a").with_float(1e21)).value(),
+            b"1e21"
+        );
+        assert_eq!(
+            build(
+                PduBuilder::new("a")
+                    .with_raw_value("1:2")
+                    .with_multi_value(true)
+            )
+            .value(),
+            b"1:2"
+        );
+        assert_eq!(
+            build(PduBuilder::new("a.b").with_integer(i64::MIN)).as_ref(),
+            b"a.b:-9223372036854775808|ms"
+        );
+    }
+
+    #[test]
+    fn build_gauges() {
+        let gauge = |builder: PduBuilder| builder.with_metric_type(MetricType::Gauge).build();
+        assert_eq!(
+            gauge(PduBuilder::new("g").with_integer(-5)),
+            Err(BuildError::InvalidValue)
+        );
+        assert_eq!(
+            gauge(PduBuilder::new("g").with_float(-0.5)),
+            Err(BuildError::InvalidValue)
+        );
+        assert_eq!(
+            PduBuilder::new("g")
+                .with_raw_value("-5")
+                .with_type("g")
+                .build(),
+            Err(BuildError::InvalidValue)
+        );
+        assert_eq!(
+            gauge(PduBuilder::new("g").with_integer(5))
+                .unwrap()
+                .as_ref(),
+            b"g:5|g"
+        );
+
+        // Deltas are written with an explicit sign and parse back as deltas
+        for (delta, line) in &[(-5.0, &b"g:-5|g"[..]), (2.5, b"g:+2.5|g"), (0.0, b"g:+0|g")] {
+            let pdu = PduBuilder::new("g")
+                .with_gauge_delta(*delta)
+                .build()
+                .unwrap();
+            assert_eq!(pdu.as_ref(), *line);
+            assert_eq!(
+                Metric::from_pdu(&pdu).unwrap().value(),
+                &MetricValue::GaugeDelta(*delta)
+            );
+        }
+        assert_eq!(
+            PduBuilder::new("g").with_gauge_delta(f64::NAN).build(),
+            Err(BuildError::InvalidValue)
+        );
+    }
+
+    #[test]
+    fn build_round_trip() {
+        let built = vec![
+            PduBuilder::new("foo.bar")
+                .with_float(0.25)
+                .with_metric_type(MetricType::Timer)
+                .with_sample_rate(0.1)
+                .with_tag("env", Some("prod:eu"))
+                .with_tag("canary", None::<&[u8]>)
+                .with_timestamp(1656581400),
+            PduBuilder::new("users")
+                .with_raw_value("a@b#c")
+                .with_type("s"),
+            PduBuilder::new("x").with_integer(1).with_type("c"),
+        ];
+        for builder in built {
+            let pdu = builder.build().unwrap();
+            let parsed = PDU::new(pdu.as_ref().to_vec().into()).unwrap();
+            assert_eq!(parsed, pdu);
+            assert_eq!(parsed.name(), pdu.name());
+            assert_eq!(parsed.value(), pdu.value());
+            assert_eq!(parsed.pdu_type(), pdu.pdu_type());
+            assert_eq!(parsed.sample_rate(), pdu.sample_rate());
+            assert_eq!(parsed.tags(), pdu.tags());
+            assert_eq!(parsed.timestamp(), pdu.timestamp());
+        }
+
+        let pdu = PduBuilder::new("name")
+            .with_raw_value("1.5:2:3")
+            .with_multi_value(true)
+            .with_type("d")
+            .build()
+            .unwrap();
+        let parsed = PDU::new_multi_value(pdu.as_ref().to_vec().into()).unwrap();
+        assert_eq!(parsed.name(), pdu.name());
+        assert_eq!(
+            parsed.values().collect::<Vec<_>>(),
+            vec![&b"1.5"[..], b"2", b"3"]
+        );
+    }
+
+    #[test]
+    fn build_errors() {
+        let valid = || PduBuilder::new("a").with_integer(1).with_type("c");
+        let invalid = vec![
+            (
+                PduBuilder::new("a").with_type("c"),
+                BuildError::MissingValue,
+            ),
+            (
+                PduBuilder::new("a").with_integer(1),
+                BuildError::MissingType,
+            ),
+            (
+                valid().with_metric_type(MetricType::Unknown),
+                BuildError::MissingType,
+            ),
+            (
+                PduBuilder::new("a|b").with_integer(1).with_type("c"),
+                BuildError::InvalidName,
+            ),
+            (
+                PduBuilder::new("").with_integer(1).with_type("c"),
+                BuildError::InvalidName,
+            ),
+            (
+                PduBuilder::new("a:b").with_integer(1).with_type("c"),
+                BuildError::InvalidName,
+            ),
+            (valid().with_raw_value("1:2"), BuildError::InvalidValue),
+            (valid().with_float(f64::NAN), BuildError::InvalidValue),
+            (valid().with_raw_value("1\n"), BuildError::InvalidValue),
+            (valid().with_type(""), BuildError::InvalidType),
+            (valid().with_sample_rate(0.0), BuildError::InvalidSampleRate),
+            (
+                valid().with_sample_rate(f64::INFINITY),
+                BuildError::InvalidSampleRate,
+            ),
+            (
+                valid().with_tag("a:b", None::<&[u8]>),
+                BuildError::InvalidTag,
+            ),
+            (valid().with_tag("a", Some("b,c")), BuildError::InvalidTag),
+            (valid().with_extension("x"), BuildError::InvalidExtension),
+            (valid().with_extension("@0.5"), BuildError::InvalidExtension),
+        ];
+        for (builder, error) in invalid {
+            assert_eq!(builder.build().unwrap_err(), error, "{:?}", builder);
+        }
+        // A later valid value replaces an invalid one
+        assert!(valid().with_float(f64::NAN).with_integer(2).build().is_ok());
+    }
+}
@@ -0,0 +1,564 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::hash::hash_bytes;
+
+/// The reason a HyperLogLog or set could not be decoded or merged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum CardinalityError {
+    /// The encoding was truncated, of an unknown version or otherwise
+    /// malformed.
+    InvalidEncoding,
+    /// The HyperLogLogs or sets have different precisions and cannot be
+    /// merged.
+    IncompatibleSets,
+}
+
+impl fmt::Display for CardinalityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CardinalityError::InvalidEncoding => f.write_str("invalid cardinality encoding"),
+            CardinalityError::IncompatibleSets => f.write_str("incompatible cardinality sets"),
+        }
+    }
+}
+
+impl std::error::Error for CardinalityError {}
+
+const HYPERLOGLOG_VERSION: u8 = 1;
+const CARDINALITY_SET_VERSION: u8 = 1;
+
+/// The number of distinct members of a set, along with bounds of roughly two
+/// standard errors around an approximate estimate. Exact counts have bounds
+/// equal to the estimate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cardinality {
+    pub estimate: f64,
+    pub lower: f64,
+    pub upper: f64,
+    pub exact: bool,
+}
+
+impl Cardinality {
+    fn exact(count: usize) -> Self {
+        let count = count as f64;
+        Cardinality {
+            estimate: count,
+            lower: count,
+            upper: count,
+            exact: true,
+        }
+    }
+}
+
+/// A HyperLogLog estimates the number of distinct members added to it in a
+/// fixed `2^precision` bytes, with a standard error of about
+/// `1.04 / sqrt(2^precision)`: 0.8% at the common precision of 14.
+///
+/// Members are hashed with a stable hash, so HyperLogLogs of the same
+/// precision built in different processes can be merged, and encoded to
+/// bytes and back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    pub const MIN_PRECISION: u8 = 4;
+    pub const MAX_PRECISION: u8 = 18;
+
+    /// Create an empty HyperLogLog with `2^precision` registers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the precision is not between `MIN_PRECISION` and
+    /// `MAX_PRECISION`.
+    pub fn new(precision: u8) -> Self {
+        assert!(
+            valid_precision(precision),
+            "precision must be between 4 and 18"
+        );
+        HyperLogLog {
+            precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    pub fn precision(&self) -> u8 {
+        self.precision
+    }
+
+    pub fn insert(&mut self, member: &[u8]) {
+        self.insert_hash(hash_bytes(member));
+    }
+
+    fn insert_hash(&mut self, hash: u64) {
+        let p = u32::from(self.precision);
+        let index = (hash >> (64 - p)) as usize;
+        // The position of the first set bit after the index bits, capped for
+        // a remainder of all zeros
+        let rank = ((hash << p).leading_zeros() + 1).min(64 - p + 1) as u8;
+        let register = &mut self.registers[index];
+        *register = (*register).max(rank);
+    }
+
+    /// Merge another HyperLogLog into this one, as if every member added to
+    /// it had been added to this one.
+    pub fn merge(&mut self, other: &HyperLogLog) -> Result<(), CardinalityError> {
+        if other.precision != self.precision {
+            return Err(CardinalityError::IncompatibleSets);
+        }
+        for (register, other) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(*other);
+        }
+        Ok(())
+    }
+
+    /// The relative standard error of estimates at this precision.
+    pub fn standard_error(&self) -> f64 {
+        1.04 / (self.registers.len() as f64).sqrt()
+    }
+
+    pub fn cardinality(&self) -> Cardinality {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let mut sum = 0.0;
+        let mut zeros = 0;
+        for register in &self.registers {
+            sum += 0.5f64.powi(i32::from(*register));
+            if *register == 0 {
+                zeros += 1;
+            }
+        }
+        let raw = alpha * m * m / sum;
+        // Linear counting is more accurate while many registers are unset
+        let estimate = if raw <= 2.5 * m && zeros > 0 {
+            m * (m / f64::from(zeros)).ln()
+        } else {
+            raw
+        };
+        let margin = 2.0 * self.standard_error() * estimate;
+        Cardinality {
+            estimate,
+            lower: (estimate - margin).max(0.0),
+            upper: estimate + margin,
+            exact: false,
+        }
+    }
+
+    /// Encode the HyperLogLog, such that `decode` returns an identical one.
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(2 + self.registers.len());
+        self.encode_into(&mut buf);
+        buf.freeze()
+    }
+
+    /// Decode a HyperLogLog written by `encode`.
+    pub fn decode(mut buf: &[u8]) -> Result<Self, CardinalityError> {
+        let hll = Self::decode_from(&mut buf)?;
+        if buf.has_remaining() {
+            return Err(CardinalityError::InvalidEncoding);
+        }
+        Ok(hll)
+    }
+
+    fn encode_into(&self, buf: &mut BytesMut) {
+        buf.put_u8(HYPERLOGLOG_VERSION);
+        buf.put_u8(self.precision);
+        buf.put_slice(&self.registers);
+    }
+
+    fn decode_from(buf: &mut &[u8]) -> Result<Self, CardinalityError> {
+        if buf.remaining() < 2 || buf.get_u8() != HYPERLOGLOG_VERSION {
+            return Err(CardinalityError::InvalidEncoding);
+        }
+        let precision = buf.get_u8();
+        if !valid_precision(precision) || buf.remaining() < 1 << precision {
+            return Err(CardinalityError::InvalidEncoding);
+        }
+        let mut hll = HyperLogLog::new(precision);
+        buf.copy_to_slice(&mut hll.registers);
+        if hll
+            .registers
+            .iter()
+            .any(|r| u32::from(*r) > 65 - u32::from(precision))
+        {
+            return Err(CardinalityError::InvalidEncoding);
+        }
+        Ok(hll)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Members {
+    Exact(HashSet<Bytes>),
+    Approximate(HyperLogLog),
+}
+
+/// A CardinalitySet counts the distinct members of a set metric. It stores
+/// members exactly until there are more than its exact limit, then switches
+/// to a `HyperLogLog` of the configured precision, bounding its memory.
+///
+/// Sets with the same precision can be merged, promoting the result when
+/// needed, and encoded to bytes and back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CardinalitySet {
+    exact_limit: usize,
+    precision: u8,
+    members: Members,
+}
+
+impl CardinalitySet {
+    /// The number of members stored exactly by default, past which a set is
+    /// smaller as a HyperLogLog of the default precision.
+    pub const DEFAULT_EXACT_LIMIT: usize = 1000;
+    pub const DEFAULT_PRECISION: u8 = 14;
+
+    /// Create an empty set storing up to `exact_limit` members exactly.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the precision is not valid for a `HyperLogLog`.
+    pub fn new(exact_limit: usize, precision: u8) -> Self {
+        assert!(
+            valid_precision(precision),
+            "precision must be between 4 and 18"
+        );
+        CardinalitySet {
+            exact_limit,
+            precision,
+            members: Members::Exact(HashSet::new()),
+        }
+    }
+
+    pub fn exact_limit(&self) -> usize {
+        self.exact_limit
+    }
+
+    pub fn precision(&self) -> u8 {
+        self.precision
+    }
+
+    /// Whether members are still stored exactly.
+    pub fn is_exact(&self) -> bool {
+        matches!(self.members, Members::Exact(_))
+    }
+
+    /// Add a member, copying it if it is new while the set is exact.
+    pub fn insert(&mut self, member: &[u8]) {
+        match &mut self.members {
+            Members::Exact(members) => {
+                if !members.contains(member) {
+                    members.insert(Bytes::copy_from_slice(member));
+                    self.promote_if_full();
+                }
+            }
+            Members::Approximate(hll) => hll.insert(member),
+        }
+    }
+
+    /// Merge another set into this one. Both sets must share a precision,
+    /// while the exact limit of this set is kept.
+    pub fn merge(&mut self, other: &CardinalitySet) -> Result<(), CardinalityError> {
+        if other.precision != self.precision {
+            return Err(CardinalityError::IncompatibleSets);
+        }
+        match &other.members {
+            Members::Exact(others) => {
+                for member in others {
+                    self.insert(member);
+                }
+            }
+            Members::Approximate(other) => {
+                self.promote();
+                if let Members::Approximate(hll) = &mut self.members {
+                    hll.merge(other)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn cardinality(&self) -> Cardinality {
+        match &self.members {
+            Members::Exact(members) => Cardinality::exact(members.len()),
+            Members::Approximate(hll) => hll.cardinality(),
+        }
+    }
+
+    /// Encode the set, such that `decode` returns an identical set. Exact
+    /// members are written in sorted order, so equal sets encode to the same
+    /// bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a member is 4 GiB or longer, or there are `u32::MAX` or more
+    /// exact members, neither of which fits the encoding.
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        buf.put_u8(CARDINALITY_SET_VERSION);
+        buf.put_u8(self.precision);
+        buf.put_u64_le(self.exact_limit as u64);
+        match &self.members {
+            Members::Exact(members) => {
+                let mut members: Vec<&Bytes> = members.iter().collect();
+                members.sort_unstable();
+                buf.put_u8(0);
+                buf.put_u32_le(encoded_len(members.len()));
+                for member in members {
+                    buf.put_u32_le(encoded_len(member.len()));
+                    buf.put_slice(member);
+                }
+            }
+            Members::Approximate(hll) => {
+                buf.put_u8(1);
+                hll.encode_into(&mut buf);
+            }
+        }
+        buf.freeze()
+    }
+
+    /// Decode a set written by `encode`.
+    pub fn decode(mut buf: &[u8]) -> Result<Self, CardinalityError> {
+        let invalid = CardinalityError::InvalidEncoding;
+        if buf.remaining() < 11 || buf.get_u8() != CARDINALITY_SET_VERSION {
+            return Err(invalid);
+        }
+        let precision = buf.get_u8();
+        if !valid_precision(precision) {
+            return Err(invalid);
+        }
+        let exact_limit = usize::try_from(buf.get_u64_le()).map_err(|_| invalid)?;
+        let members = match buf.get_u8() {
+            0 => {
+                if buf.remaining() < 4 {
+                    return Err(invalid);
+                }
+                let count = buf.get_u32_le() as usize;
+                // Each member takes at least its four byte length
+                let mut members = HashSet::with_capacity(count.min(buf.remaining() / 4));
+                for _ in 0..count {
+                    if buf.remaining() < 4 {
+                        return Err(invalid);
+                    }
+                    let len = buf.get_u32_le() as usize;
+                    if buf.remaining() < len {
+                        return Err(invalid);
+                    }
+                    if !members.insert(buf.copy_to_bytes(len)) {
+                        return Err(invalid);
+                    }
+                }
+                Members::Exact(members)
+            }
+            1 => {
+                let hll = HyperLogLog::decode_from(&mut buf)?;
+                if hll.precision != precision {
+                    return Err(invalid);
+                }
+                Members::Approximate(hll)
+            }
+            _ => return Err(invalid),
+        };
+        if buf.has_remaining() {
+            return Err(invalid);
+        }
+        let mut set = CardinalitySet {
+            exact_limit,
+            precision,
+            members,
+        };
+        set.promote_if_full();
+        Ok(set)
+    }
+
+    fn promote_if_full(&mut self) {
+        if matches!(&self.members, Members::Exact(members) if members.len() > self.exact_limit) {
+            self.promote();
+        }
+    }
+
+    fn promote(&mut self) {
+        if let Members::Exact(members) = &self.members {
+            let mut hll = HyperLogLog::new(self.precision);
+            for member in members {
+                hll.insert(member);
+            }
+            self.members = Members::Approximate(hll);
+        }
+    }
+}
+
+impl Default for CardinalitySet {
+    fn default() -> Self {
+        CardinalitySet::new(Self::DEFAULT_EXACT_LIMIT, Self::DEFAULT_PRECISION)
+    }
+}
+
+fn encoded_len(len: usize) -> u32 {
+    u32::try_from(len).expect("length does not fit the encoding")
+}
+
+/// Whether a precision is supported by `HyperLogLog`.
+pub(crate) fn valid_precision(precision: u8) -> bool {
+    (HyperLogLog::MIN_PRECISION..=HyperLogLog::MAX_PRECISION).contains(&precision)
+}
+
+#[cfg(test)]
+pub mod atest {
+    use super::*;
+
+    fn members(range: std::ops::Range<u32>) -> impl Iterator<Item = Vec<u8>> {
+        range.map(|i| format!("user{}", i).into_bytes())
+    }
+
+    #[test]
+    fn hyperloglog_estimates() {
+        for &(precision, count) in &[(14, 100u32), (14, 100_000), (10, 50_000)] {
+            let mut hll = HyperLogLog::new(precision);
+            for member in members(0..count) {
+                hll.insert(&member);
+                hll.insert(&member);
+            }
+            let cardinality = hll.cardinality();
+            let count = f64::from(count);
+            assert!(!cardinality.exact);
+            assert!(
+                cardinality.lower <= count && count <= cardinality.upper,
+                "{:?} does not cover {}",
+                cardinality,
+                count
+            );
+        }
+        assert_eq!(HyperLogLog::new(12).cardinality().estimate, 0.0);
+    }
+
+    #[test]
+    fn merge_hyperloglogs() {
+        let mut left = HyperLogLog::new(12);
+        let mut right = HyperLogLog::new(12);
+        let mut whole = HyperLogLog::new(12);
+        for member in members(0..6000) {
+            left.insert(&member);
+            whole.insert(&member);
+        }
+        for member in members(4000..10000) {
+            right.insert(&member);
+            whole.insert(&member);
+        }
+        left.merge(&right).unwrap();
+        assert_eq!(left, whole);
+        assert_eq!(
+            left.merge(&HyperLogLog::new(13)),
+            Err(CardinalityError::IncompatibleSets)
+        );
+
+        assert_eq!(HyperLogLog::decode(&left.encode()), Ok(left.clone()));
+        let encoded = left.encode();
+        assert_eq!(
+            HyperLogLog::decode(&encoded[..encoded.len() - 1]),
+            Err(CardinalityError::InvalidEncoding)
+        );
+    }
+
+    #[test]
+    fn cardinality_set_promotion() {
+        let mut set = CardinalitySet::new(100, 12);
+        for member in members(0..100) {
+            set.insert(&member);
+        }
+        set.insert(b"user0");
+        assert!(set.is_exact());
+        assert_eq!(set.cardinality(), Cardinality::exact(100));
+
+        set.insert(b"user100");
+        assert!(!set.is_exact());
+        for member in members(101..5000) {
+            set.insert(&member);
+        }
+        let cardinality = set.cardinality();
+        assert!(cardinality.lower <= 5000.0 && 5000.0 <= cardinality.upper);
+    }
+
+    #[test]
+    fn merge_cardinality_sets() {
+        let mut left = CardinalitySet::new(100, 12);
+        let mut right = CardinalitySet::new(100, 12);
+        for member in members(0..60) {
+            left.insert(&member);
+        }
+        for member in members(30..90) {
+            right.insert(&member);
+        }
+        left.merge(&right).unwrap();
+        assert_eq!(left.cardinality(), Cardinality::exact(90));
+
+        // Merging past the exact limit promotes the set
+        for member in members(90..200) {
+            right.insert(&member);
+        }
+        assert!(!right.is_exact());
+        left.merge(&right).unwrap();
+        assert!(!left.is_exact());
+        let cardinality = left.cardinality();
+        assert!(cardinality.lower <= 200.0 && 200.0 <= cardinality.upper);
+
+        assert_eq!(
+            left.merge(&CardinalitySet::new(100, 14)),
+            Err(CardinalityError::IncompatibleSets)
+        );
+    }
+
+    #[test]
+    fn encode_cardinality_sets() {
+        let mut set = CardinalitySet::new(10, 8);
+        set.insert(b"a");
+        set.insert(b"");
+        assert_eq!(CardinalitySet::decode(&set.encode()), Ok(set.clone()));
+        for member in members(0..20) {
+            set.insert(&member);
+        }
+        assert_eq!(CardinalitySet::decode(&set.encode()), Ok(set.clone()));
+        let encoded = set.encode();
+        assert_eq!(
+            CardinalitySet::decode(&encoded[..encoded.len() - 1]),
+            Err(CardinalityError::InvalidEncoding)
+        );
+        assert_eq!(
+            CardinalitySet::decode(b"\x01\x08"),
+            Err(CardinalityError::InvalidEncoding)
+        );
+    }
+
+    #[test]
+    fn encode_exact_sets_deterministically() {
+        let mut forward = CardinalitySet::new(100, 8);
+        let mut backward = CardinalitySet::new(100, 8);
+        for member in members(0..50) {
+            forward.insert(&member);
+        }
+        for member in members(0..50).collect::<Vec<_>>().iter().rev() {
+            backward.insert(member);
+        }
+        assert_eq!(forward.encode(), backward.encode());
+
+        let mut set = CardinalitySet::new(10, 8);
+        set.insert(b"a");
+        let mut encoded = set.encode().to_vec();
+        encoded[11] = 2;
+        encoded.extend_from_slice(b"\x01\x00\x00\x00a");
+        assert_eq!(
+            CardinalitySet::decode(&encoded),
+            Err(CardinalityError::InvalidEncoding)
+        );
+    }
+}
@@ -0,0 +1,239 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use memchr::memchr;
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::pdu::{ParseError, ParseErrorKind, ParserConfig, PDU};
+use crate::split::LineError;
+
+/// The default maximum line length accepted by a `StatsdCodec`.
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 8192;
+
+/// A StatsdCodec frames newline delimited statsd messages on a byte stream,
+/// such as a TCP or Unix stream socket, into PDUs.
+///
+/// Lines which fail to parse, or which are longer than the maximum line
+/// length, are yielded as a `LineError` item rather than a stream error. The
+/// codec then resynchronizes on the next newline, so a single bad client
+/// write does not poison the rest of the stream.
+#[derive(Debug, Clone)]
+pub struct StatsdCodec {
+    max_length: usize,
+    config: ParserConfig,
+    // Index into the buffer up to which we have already scanned for a newline
+    next_index: usize,
+    // Set while skipping the remainder of an oversized line
+    discarding: bool,
+}
+
+impl StatsdCodec {
+    pub fn new() -> Self {
+        Self::with_max_length(DEFAULT_MAX_LINE_LENGTH)
+    }
+
+    pub fn with_max_length(max_length: usize) -> Self {
+        StatsdCodec {
+            max_length,
+            config: ParserConfig::default(),
+            next_index: 0,
+            discarding: false,
+        }
+    }
+
+    /// Parse lines with the given configuration. Its maximum line length, if
+    /// set, replaces the default maximum line length of the codec.
+    pub fn with_config(config: ParserConfig) -> Self {
+        StatsdCodec {
+            max_length: config.max_line_length().unwrap_or(DEFAULT_MAX_LINE_LENGTH),
+            config,
+            next_index: 0,
+            discarding: false,
+        }
+    }
+
+    pub fn max_length(&self) -> usize {
+        self.max_length
+    }
+
+    fn parse(&self, mut line: Bytes) -> Option<Result<PDU, LineError>> {
+        if line.last() == Some(&b'\r') {
+            line.truncate(line.len() - 1);
+        }
+        if line.is_empty() {
+            return None;
+        }
+        Some(
+            PDU::parse_with(&self.config, line.clone())
+                .map_err(|error| LineError::new(line, error)),
+        )
+    }
+}
+
+impl Default for StatsdCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for StatsdCodec {
+    type Item = Result<PDU, LineError>;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, io::Error> {
+        loop {
+            // Never scan further than one byte past the maximum line length,
+            // which is enough to find the newline terminating a maximal line.
+            let read_to = std::cmp::min(self.max_length.saturating_add(1), buf.len());
+            let newline = memchr(b'\n', &buf[self.next_index..read_to]);
+
+            match (self.discarding, newline) {
+                (true, Some(offset)) => {
+                    buf.advance(self.next_index + offset + 1);
+                    self.discarding = false;
+                    self.next_index = 0;
+                }
+                (true, None) => {
+                    buf.advance(read_to);
+                    self.next_index = 0;
+                    if buf.is_empty() {
+                        return Ok(None);
+                    }
+                }
+                (false, Some(offset)) => {
+                    let newline_index = self.next_index + offset;
+                    self.next_index = 0;
+                    let line = buf.split_to(newline_index + 1).freeze();
+                    if let Some(item) = self.parse(line.slice(..newline_index)) {
+                        return Ok(Some(item));
+                    }
+                }
+                (false, None) if buf.len() > self.max_length => {
+                    self.discarding = true;
+                    self.next_index = 0;
+                    let line = buf.split_to(self.max_length).freeze();
+                    let error = ParseError::new(ParseErrorKind::LineTooLong, self.max_length);
+                    return Ok(Some(Err(LineError::new(line, error))));
+                }
+                (false, None) => {
+                    self.next_index = read_to;
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, io::Error> {
+        if let Some(item) = self.decode(buf)? {
+            return Ok(Some(item));
+        }
+        self.next_index = 0;
+        if self.discarding {
+            self.discarding = false;
+            buf.clear();
+            return Ok(None);
+        }
+        // A final line without a trailing newline
+        let line = buf.split().freeze();
+        Ok(self.parse(line))
+    }
+}
+
+impl Encoder<PDU> for StatsdCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, pdu: PDU, buf: &mut BytesMut) -> Result<(), io::Error> {
+        buf.reserve(pdu.len() + 1);
+        buf.put(pdu.as_ref());
+        buf.put_u8(b'\n');
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod atest {
+    use super::*;
+
+    fn decode_all(codec: &mut StatsdCodec, buf: &mut BytesMut) -> Vec<Result<PDU, LineError>> {
+        let mut items = Vec::new();
+        while let Some(item) = codec.decode(buf).unwrap() {
+            items.push(item);
+        }
+        items
+    }
+
+    #[test]
+    fn decode_lines() {
+        let mut codec = StatsdCodec::new();
+        let mut buf = BytesMut::from(&b"foo:1|c\r\n\nbar:2|g\nbaz:"[..]);
+        let items = decode_all(&mut codec, &mut buf);
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].as_ref().unwrap().name(), b"foo");
+        assert_eq!(items[1].as_ref().unwrap().name(), b"bar");
+
+        buf.extend_from_slice(b"3|ms\n");
+        let pdu = codec.decode(&mut buf).unwrap().unwrap().unwrap();
+        assert_eq!(pdu.name(), b"baz");
+        assert_eq!(pdu.value(), b"3");
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_garbage_resyncs() {
+        let mut codec = StatsdCodec::new();
+        let mut buf = BytesMut::from(&b"garbage\nfoo:1|c\n"[..]);
+        let items = decode_all(&mut codec, &mut buf);
+        assert_eq!(items.len(), 2);
+        let error = items[0].as_ref().unwrap_err();
+        assert_eq!(error.line().as_ref(), b"garbage");
+        assert_eq!(items[1].as_ref().unwrap().name(), b"foo");
+    }
+
+    #[test]
+    fn decode_oversized_resyncs() {
+        let mut codec = StatsdCodec::with_max_length(8);
+        let mut buf = BytesMut::from(&b"long.metric.name"[..]);
+        let error = codec.decode(&mut buf).unwrap().unwrap().unwrap_err();
+        assert_eq!(error.error().kind(), ParseErrorKind::LineTooLong);
+        assert_eq!(error.line().as_ref(), b"long.met");
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(b":1|c\nfoo:1|c\n");
+        let pdu = codec.decode(&mut buf).unwrap().unwrap().unwrap();
+        assert_eq!(pdu.name(), b"foo");
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn decode_with_config() {
+        let config = ParserConfig::strict().with_max_line_length(8);
+        let mut codec = StatsdCodec::with_config(config);
+        assert_eq!(codec.max_length(), 8);
+        let mut buf = BytesMut::from(&b"foo:|c\nfoo:1|c\n"[..]);
+        let items = decode_all(&mut codec, &mut buf);
+        assert_eq!(
+            items[0].as_ref().unwrap_err().error().kind(),
+            ParseErrorKind::EmptyField
+        );
+        assert_eq!(items[1].as_ref().unwrap().name(), b"foo");
+    }
+
+    #[test]
+    fn decode_eof_line() {
+        let mut codec = StatsdCodec::new();
+        let mut buf = BytesMut::from(&b"foo:1|c"[..]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        let pdu = codec.decode_eof(&mut buf).unwrap().unwrap().unwrap();
+        assert_eq!(pdu.name(), b"foo");
+        assert!(codec.decode_eof(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn encode_pdu() {
+        let mut codec = StatsdCodec::new();
+        let mut buf = BytesMut::new();
+        let pdu = PDU::new(Bytes::from_static(b"foo:1|c|#a:b")).unwrap();
+        codec.encode(pdu.clone(), &mut buf).unwrap();
+        codec.encode(pdu, &mut buf).unwrap();
+        assert_eq!(buf.as_ref(), b"foo:1|c|#a:b\nfoo:1|c|#a:b\n");
+    }
+}
@@ -0,0 +1,291 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use memchr::memchr;
+
+use crate::pdu::{ParseError, ParseErrorKind};
+use crate::tags::Tags;
+
+/// The priority of a DogStatsD event (`|p:`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventPriority {
+    Normal,
+    Low,
+}
+
+impl EventPriority {
+    pub fn from_bytes(priority: &[u8]) -> Option<Self> {
+        match priority {
+            b"normal" => Some(EventPriority::Normal),
+            b"low" => Some(EventPriority::Low),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(self) -> &'static [u8] {
+        match self {
+            EventPriority::Normal => b"normal",
+            EventPriority::Low => b"low",
+        }
+    }
+}
+
+/// The alert type of a DogStatsD event (`|t:`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventAlertType {
+    Error,
+    Warning,
+    Info,
+    Success,
+}
+
+impl EventAlertType {
+    pub fn from_bytes(alert_type: &[u8]) -> Option<Self> {
+        match alert_type {
+            b"error" => Some(EventAlertType::Error),
+            b"warning" => Some(EventAlertType::Warning),
+            b"info" => Some(EventAlertType::Info),
+            b"success" => Some(EventAlertType::Success),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(self) -> &'static [u8] {
+        match self {
+            EventAlertType::Error => b"error",
+            EventAlertType::Warning => b"warning",
+            EventAlertType::Info => b"info",
+            EventAlertType::Success => b"success",
+        }
+    }
+}
+
+/// An Event is a DogStatsD event message of the form
+/// `_e{<title length>,<text length>}:<title>|<text>|d:<timestamp>|h:<hostname>|k:<aggregation key>|p:<priority>|s:<source type>|t:<alert type>|#<tags>`
+/// where every field following the text is optional.
+///
+/// Escaped newlines (`\n`) in the title and text are unescaped. Fields share
+/// the allocation of the parsed line unless they needed unescaping.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    title: Bytes,
+    text: Bytes,
+    timestamp: Option<u64>,
+    hostname: Option<Bytes>,
+    aggregation_key: Option<Bytes>,
+    priority: Option<EventPriority>,
+    source_type: Option<Bytes>,
+    alert_type: Option<EventAlertType>,
+    tags: Option<Bytes>,
+}
+
+impl Event {
+    pub fn title(&self) -> &[u8] {
+        &self.title
+    }
+
+    pub fn text(&self) -> &[u8] {
+        &self.text
+    }
+
+    /// The unix timestamp of the event (`|d:`), in seconds.
+    pub fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+
+    pub fn hostname(&self) -> Option<&[u8]> {
+        self.hostname.as_deref()
+    }
+
+    pub fn aggregation_key(&self) -> Option<&[u8]> {
+        self.aggregation_key.as_deref()
+    }
+
+    pub fn priority(&self) -> Option<EventPriority> {
+        self.priority
+    }
+
+    pub fn source_type(&self) -> Option<&[u8]> {
+        self.source_type.as_deref()
+    }
+
+    pub fn alert_type(&self) -> Option<EventAlertType> {
+        self.alert_type
+    }
+
+    pub fn tags(&self) -> Option<&[u8]> {
+        self.tags.as_deref()
+    }
+
+    /// Iterate the individual tags of the tag section, if any.
+    pub fn tag_iter(&self) -> Tags<'_> {
+        self.tags().map_or_else(Tags::empty, Tags::new)
+    }
+
+    /// Parse a single event line. The declared title and text lengths are
+    /// honoured, so both may contain `|`. Later occurrences of an optional
+    /// field take precedence over earlier ones.
+    pub fn parse(line: Bytes) -> Result<Self, ParseError> {
+        let length = line.len();
+        let header = |offset| ParseError::new(ParseErrorKind::InvalidHeader, offset);
+        let mismatch = |offset| ParseError::new(ParseErrorKind::LengthMismatch, offset);
+
+        if !line.starts_with(b"_e{") {
+            return Err(header(0));
+        }
+        let close = memchr(b'}', &line).ok_or_else(|| header(length))?;
+        let lengths = &line[3..close];
+        let comma = memchr(b',', lengths).ok_or_else(|| header(3))?;
+        let title_len = parse_decimal(&lengths[..comma]).ok_or_else(|| header(3))?;
+        let text_len = parse_decimal(&lengths[comma + 1..]).ok_or_else(|| header(comma + 4))?;
+        if line.get(close + 1) != Some(&b':') {
+            return Err(header(close + 1));
+        }
+
+        let title_start = close + 2;
+        let title_end = title_start
+            .checked_add(title_len as usize)
+            .filter(|end| line.get(*end) == Some(&b'|'))
+            .ok_or_else(|| mismatch(title_start))?;
+        let text_start = title_end + 1;
+        let text_end = text_start
+            .checked_add(text_len as usize)
+            .filter(|end| *end == length || line.get(*end) == Some(&b'|'))
+            .ok_or_else(|| mismatch(text_start))?;
+
+        let mut event = Event {
+            title: unescape_newlines(line.slice(title_start..title_end)),
+            text: unescape_newlines(line.slice(text_start..text_end)),
+            timestamp: None,
+            hostname: None,
+            aggregation_key: None,
+            priority: None,
+            source_type: None,
+            alert_type: None,
+            tags: None,
+        };
+
+        let mut index = text_end;
+        while index < length {
+            // Every section starts with '|' and runs until the next one
+            let start = index + 1;
+            let end = memchr(b'|', &line[start..]).map_or(length, |v| v + start);
+            let invalid = || ParseError::new(ParseErrorKind::InvalidField, start);
+            let field = &line[start..end];
+            match field {
+                [b'd', b':', v @ ..] => {
+                    event.timestamp = Some(parse_decimal(v).ok_or_else(invalid)?);
+                }
+                [b'h', b':', ..] => event.hostname = Some(line.slice(start + 2..end)),
+                [b'k', b':', ..] => event.aggregation_key = Some(line.slice(start + 2..end)),
+                [b'p', b':', v @ ..] => {
+                    event.priority = Some(EventPriority::from_bytes(v).ok_or_else(invalid)?);
+                }
+                [b's', b':', ..] => event.source_type = Some(line.slice(start + 2..end)),
+                [b't', b':', v @ ..] => {
+                    event.alert_type = Some(EventAlertType::from_bytes(v).ok_or_else(invalid)?);
+                }
+                [b'#', ..] => event.tags = Some(line.slice(start + 1..end)),
+                _ => return Err(ParseError::new(ParseErrorKind::UnknownExtension, index)),
+            }
+            index = end;
+        }
+        Ok(event)
+    }
+}
+
+/// Parse an unsigned decimal number, without sign or whitespace.
+pub(crate) fn parse_decimal(buf: &[u8]) -> Option<u64> {
+    if buf.is_empty() || !buf.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    std::str::from_utf8(buf).ok()?.parse().ok()
+}
+
+/// Replace escaped `\n` sequences with newlines, sharing the original
+/// allocation when there is nothing to unescape.
+pub(crate) fn unescape_newlines(field: Bytes) -> Bytes {
+    if memchr(b'\\', &field).is_none() {
+        return field;
+    }
+    let mut buf = BytesMut::with_capacity(field.len());
+    let mut index = 0;
+    while index < field.len() {
+        if field[index] == b'\\' && field.get(index + 1) == Some(&b'n') {
+            buf.put_u8(b'\n');
+            index += 2;
+        } else {
+            buf.put_u8(field[index]);
+            index += 1;
+        }
+    }
+    buf.freeze()
+}
+
+#[cfg(test)]
+pub mod atest {
+    use super::*;
+    use crate::tags::Tag;
+
+    #[test]
+    fn simple_event() {
+        let event = Event::parse(Bytes::from_static(b"_e{5,4}:title|text")).unwrap();
+        assert_eq!(event.title(), b"title");
+        assert_eq!(event.text(), b"text");
+        assert_eq!(event.timestamp(), None);
+        assert_eq!(event.priority(), None);
+        assert_eq!(event.tags(), None);
+    }
+
+    #[test]
+    fn full_event() {
+        let event = Event::parse(Bytes::from_static(
+            b"_e{5,4}:title|text|d:123|h:host|k:key|p:low|s:src|t:warning|#a:b,c",
+        ))
+        .unwrap();
+        assert_eq!(event.title(), b"title");
+        assert_eq!(event.text(), b"text");
+        assert_eq!(event.timestamp(), Some(123));
+        assert_eq!(event.hostname().unwrap(), b"host");
+        assert_eq!(event.aggregation_key().unwrap(), b"key");
+        assert_eq!(event.priority(), Some(EventPriority::Low));
+        assert_eq!(event.source_type().unwrap(), b"src");
+        assert_eq!(event.alert_type(), Some(EventAlertType::Warning));
+        let tags: Vec<Tag> = event.tag_iter().collect();
+        assert_eq!(tags, vec![Tag::new(b"a", Some(b"b")), Tag::new(b"c", None)]);
+    }
+
+    #[test]
+    fn event_declared_lengths() {
+        // The declared lengths allow '|' inside the title and text
+        let event = Event::parse(Bytes::from_static(b"_e{3,5}:a|b|c|d|e|p:normal")).unwrap();
+        assert_eq!(event.title(), b"a|b");
+        assert_eq!(event.text(), b"c|d|e");
+        assert_eq!(event.priority(), Some(EventPriority::Normal));
+    }
+
+    #[test]
+    fn event_escaped_newlines() {
+        let event = Event::parse(Bytes::from_static(b"_e{7,10}:a\\nb\\\\c|line\\nline")).unwrap();
+        assert_eq!(event.title(), b"a\nb\\\\c");
+        assert_eq!(event.text(), b"line\nline");
+    }
+
+    #[test]
+    fn invalid_events() {
+        let invalid: Vec<(&'static [u8], ParseErrorKind)> = vec![
+            (b"_e5,4}:title|text", ParseErrorKind::InvalidHeader),
+            (b"_e{5}:title|text", ParseErrorKind::InvalidHeader),
+            (b"_e{x,4}:title|text", ParseErrorKind::InvalidHeader),
+            (b"_e{5,4}title|text", ParseErrorKind::InvalidHeader),
+            (b"_e{6,4}:title|text", ParseErrorKind::LengthMismatch),
+            (b"_e{5,3}:title|text", ParseErrorKind::LengthMismatch),
+            (b"_e{5,5}:title|text", ParseErrorKind::LengthMismatch),
+            (b"_e{5,4}:title|text|p:urgent", ParseErrorKind::InvalidField),
+            (b"_e{5,4}:title|text|d:soon", ParseErrorKind::InvalidField),
+            (b"_e{5,4}:title|text|x:y", ParseErrorKind::UnknownExtension),
+        ];
+        for (line, kind) in invalid {
+            let err = Event::parse(Bytes::from_static(line)).unwrap_err();
+            assert_eq!(err.kind(), kind, "{}", String::from_utf8_lossy(line));
+        }
+    }
+}
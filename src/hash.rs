@@ -0,0 +1,66 @@
+/// A 64-bit FNV-1a hasher with a murmur3 finalizer. Unlike the standard
+/// library's hashers it is unseeded and fixed, so hashes can be stored,
+/// exchanged between processes and compared across releases.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct StableHasher(u64);
+
+impl StableHasher {
+    const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    pub(crate) fn new() -> Self {
+        StableHasher(Self::OFFSET)
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(Self::PRIME);
+        }
+    }
+
+    pub(crate) fn write_u8(&mut self, byte: u8) {
+        self.write(&[byte]);
+    }
+
+    pub(crate) fn write_u64(&mut self, value: u64) {
+        self.write(&value.to_le_bytes());
+    }
+
+    /// Finish the hash, mixing the state so every bit depends on every input
+    /// byte. FNV alone leaves the high bits of short inputs poorly mixed.
+    pub(crate) fn finish(&self) -> u64 {
+        let mut h = self.0;
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        h ^= h >> 33;
+        h
+    }
+}
+
+pub(crate) fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hasher = StableHasher::new();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+#[cfg(test)]
+pub mod atest {
+    use super::*;
+
+    #[test]
+    fn stable_hashes() {
+        // Hashes are stored and exchanged, so they must never change
+        assert_eq!(hash_bytes(b""), 0xefd0_1f60_ba99_2926);
+        assert_eq!(hash_bytes(b"a"), 0x82a2_a958_a9be_ce5b);
+        assert_eq!(hash_bytes(b"foo.bar"), 0x19f7_bc4f_9a7f_8041);
+        assert_eq!(hash_bytes(b"user1"), 0xcaf6_72d6_3552_cd57);
+
+        let mut hasher = StableHasher::new();
+        hasher.write(b"foo.");
+        hasher.write(b"bar");
+        assert_eq!(hasher.finish(), hash_bytes(b"foo.bar"));
+    }
+}
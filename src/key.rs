@@ -0,0 +1,319 @@
+use bytes::Bytes;
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+
+use crate::hash::StableHasher;
+use crate::metric::{Metric, MetricType, MetricValue};
+use crate::pdu::PDU;
+
+/// The class of a metric type. Types which are aggregated alike, such as
+/// timers, histograms and distributions, share a class. Every unrecognized
+/// type is of the `Unknown` class, while keys keep the raw type so unknown
+/// types are still told apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum MetricClass {
+    Counter,
+    Gauge,
+    /// Timers, histograms and distributions.
+    Timer,
+    Set,
+    Unknown,
+}
+
+impl MetricClass {
+    pub fn from_type(metric_type: MetricType) -> Self {
+        match metric_type {
+            MetricType::Counter => MetricClass::Counter,
+            MetricType::Gauge => MetricClass::Gauge,
+            MetricType::Timer | MetricType::Histogram | MetricType::Distribution => {
+                MetricClass::Timer
+            }
+            MetricType::Set => MetricClass::Set,
+            MetricType::Unknown => MetricClass::Unknown,
+        }
+    }
+}
+
+/// The identity of a series: a metric name, the class of its type and its
+/// tags, sorted and deduplicated so the order tags were sent in does not
+/// matter. `foo:1|c|#a:1,b:2` and `foo:1|c|#b:2,a:1` have the same key.
+///
+/// The 64-bit hash of the key is computed once, when it is built, so keys
+/// are cheap to look up in a `HashMap`. The hash is stable across processes
+/// and releases, so it can also be used to pick a shard for a series.
+#[derive(Debug, Clone)]
+pub struct MetricKey {
+    hash: u64,
+    name: Bytes,
+    class: MetricClass,
+    unknown_type: Option<Bytes>,
+    tags: Vec<(Bytes, Option<Bytes>)>,
+}
+
+impl MetricKey {
+    /// Derive the key of a PDU, sharing its allocation. Only the tags are
+    /// parsed; the value is not interpreted.
+    pub fn from_pdu(pdu: &PDU) -> Self {
+        let class = MetricClass::from_type(MetricType::from_bytes(pdu.pdu_type()));
+        let unknown_type = match class {
+            MetricClass::Unknown => Some(pdu.slice_ref(pdu.pdu_type())),
+            _ => None,
+        };
+        Self::new(
+            pdu.slice_ref(pdu.name()),
+            class,
+            unknown_type,
+            pdu.tag_pairs(),
+        )
+    }
+
+    pub fn from_metric(metric: &Metric) -> Self {
+        let unknown_type = match metric.value() {
+            MetricValue::Unknown { pdu_type, .. } => Some(pdu_type.clone()),
+            _ => None,
+        };
+        Self::new(
+            metric.name_bytes().clone(),
+            MetricClass::from_type(metric.metric_type()),
+            unknown_type,
+            metric.tag_pairs().to_vec(),
+        )
+    }
+
+    fn new(
+        name: Bytes,
+        class: MetricClass,
+        unknown_type: Option<Bytes>,
+        mut tags: Vec<(Bytes, Option<Bytes>)>,
+    ) -> Self {
+        tags.sort_unstable();
+        tags.dedup();
+
+        // Every field is length prefixed, so no two keys hash the same input
+        let mut hasher = StableHasher::new();
+        hasher.write_u64(name.len() as u64);
+        hasher.write(&name);
+        hasher.write_u8(class as u8);
+        // Only keys of the unknown class have a raw type, so hashes of known
+        // types are unaffected
+        if let Some(pdu_type) = &unknown_type {
+            hasher.write_u64(pdu_type.len() as u64);
+            hasher.write(pdu_type);
+        }
+        hasher.write_u64(tags.len() as u64);
+        for (key, value) in &tags {
+            hasher.write_u64(key.len() as u64);
+            hasher.write(key);
+            match value {
+                Some(value) => {
+                    hasher.write_u8(1);
+                    hasher.write_u64(value.len() as u64);
+                    hasher.write(value);
+                }
+                None => hasher.write_u8(0),
+            }
+        }
+
+        MetricKey {
+            hash: hasher.finish(),
+            name,
+            class,
+            unknown_type,
+            tags,
+        }
+    }
+
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    pub fn class(&self) -> MetricClass {
+        self.class
+    }
+
+    /// The raw type field of a key of the `Unknown` class, such as `kv`.
+    pub fn unknown_type(&self) -> Option<&[u8]> {
+        self.unknown_type.as_deref()
+    }
+
+    pub fn tags(&self) -> &[(Bytes, Option<Bytes>)] {
+        &self.tags
+    }
+
+    /// The precomputed, stable 64-bit hash of the key.
+    pub fn hash64(&self) -> u64 {
+        self.hash
+    }
+
+    /// Pick one of `shards` shards for the series, spreading keys evenly.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is zero.
+    pub fn shard(&self, shards: usize) -> usize {
+        assert!(shards > 0, "shards must be positive");
+        ((u128::from(self.hash) * shards as u128) >> 64) as usize
+    }
+
+    /// Copy the key out of the buffer it was parsed from, so a long lived
+    /// series does not keep the whole datagram alive.
+    pub(crate) fn detach(&self) -> Self {
+        MetricKey {
+            hash: self.hash,
+            name: Bytes::copy_from_slice(&self.name),
+            class: self.class,
+            unknown_type: self.unknown_type.as_deref().map(Bytes::copy_from_slice),
+            tags: self
+                .tags
+                .iter()
+                .map(|(key, value)| {
+                    (
+                        Bytes::copy_from_slice(key),
+                        value.as_deref().map(Bytes::copy_from_slice),
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+impl PartialEq for MetricKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash
+            && self.class == other.class
+            && self.unknown_type == other.unknown_type
+            && self.name == other.name
+            && self.tags == other.tags
+    }
+}
+
+impl Eq for MetricKey {}
+
+impl Hash for MetricKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
+}
+
+/// Keys are ordered by name, then class and any unknown type, then tags, so
+/// sorted keys group the series of a metric together.
+impl Ord for MetricKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.name
+            .cmp(&other.name)
+            .then(self.class.cmp(&other.class))
+            .then_with(|| self.unknown_type.cmp(&other.unknown_type))
+            .then_with(|| self.tags.cmp(&other.tags))
+    }
+}
+
+impl PartialOrd for MetricKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+pub mod atest {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn key(line: &'static [u8]) -> MetricKey {
+        MetricKey::from_pdu(&PDU::new(Bytes::from_static(line)).unwrap())
+    }
+
+    #[test]
+    fn stable_key_hashes() {
+        // Hashes and shards are shared between processes, so they must never
+        // change
+        let tagged = key(b"foo.bar:1|c|#env:prod,canary");
+        assert_eq!(tagged.hash64(), 0x3153_81ac_c45d_98f2);
+        assert_eq!(
+            [tagged.shard(2), tagged.shard(16), tagged.shard(1000)],
+            [0, 3, 192]
+        );
+        let timer = key(b"lat:20|ms");
+        assert_eq!(timer.hash64(), 0xab65_2c52_6722_e051);
+        assert_eq!(
+            [timer.shard(2), timer.shard(16), timer.shard(1000)],
+            [1, 10, 669]
+        );
+    }
+
+    #[test]
+    fn key_identity() {
+        let a = key(b"foo:1|c|#a:1,b:2");
+        assert_eq!(a, key(b"foo:7|c|@0.5|#b:2,a:1"));
+        assert_eq!(a, key(b"foo:1|c|#b:2,a:1,a:1"));
+        assert_eq!(a.hash64(), key(b"foo:1|c|#b:2,a:1").hash64());
+        assert_eq!(key(b"foo:1|ms"), key(b"foo:1|d"));
+        assert_eq!(key(b"foo:1|ms").class(), MetricClass::Timer);
+
+        let different = [
+            key(b"foo:1|g|#a:1,b:2"),
+            key(b"bar:1|c|#a:1,b:2"),
+            key(b"foo:1|c|#a:1"),
+            key(b"foo:1|c|#a:1,b"),
+            key(b"foo:1|c|#a:1,b:"),
+            key(b"foo:1|c|#a:1,b:2,c:3"),
+        ];
+        for other in &different {
+            assert_ne!(&a, other);
+            assert_ne!(a.hash64(), other.hash64());
+        }
+
+        let metric =
+            Metric::from_pdu(&PDU::new(Bytes::from_static(b"foo:1|c|#b:2,a:1")).unwrap()).unwrap();
+        let mut counts = HashMap::new();
+        *counts.entry(MetricKey::from_metric(&metric)).or_insert(0) += 1;
+        *counts.entry(a.clone()).or_insert(0) += 1;
+        assert_eq!(counts[&a], 2);
+    }
+
+    #[test]
+    fn unknown_type_keys() {
+        let x = key(b"foo:1|x");
+        assert_eq!(x.class(), MetricClass::Unknown);
+        assert_eq!(x.unknown_type(), Some(&b"x"[..]));
+        assert_eq!(key(b"foo:1|c").unknown_type(), None);
+        assert_ne!(x, key(b"foo:1|y"));
+        assert_ne!(x.hash64(), key(b"foo:1|y").hash64());
+        assert!(x < key(b"foo:1|y"));
+
+        let metric =
+            Metric::from_pdu(&PDU::new(Bytes::from_static(b"foo:abc|x")).unwrap()).unwrap();
+        assert_eq!(MetricKey::from_metric(&metric), x);
+        assert_eq!(x.detach(), x);
+    }
+
+    #[test]
+    fn key_order_and_shards() {
+        let mut keys = [
+            key(b"foo:1|g"),
+            key(b"bar:1|c|#z"),
+            key(b"foo:1|c|#b"),
+            key(b"foo:1|c|#a"),
+        ];
+        keys.sort();
+        let order: Vec<(&[u8], MetricClass)> = keys.iter().map(|k| (k.name(), k.class())).collect();
+        assert_eq!(
+            order,
+            vec![
+                (&b"bar"[..], MetricClass::Counter),
+                (b"foo", MetricClass::Counter),
+                (b"foo", MetricClass::Counter),
+                (b"foo", MetricClass::Gauge),
+            ]
+        );
+        assert_eq!(keys[1].tags()[0].0, "a");
+
+        let mut shards = [0; 4];
+        for i in 0..4000 {
+            let line = format!("series.{}:1|c", i);
+            let key = MetricKey::from_pdu(&PDU::new(Bytes::from(line)).unwrap());
+            assert_eq!(key.shard(4), key.detach().shard(4));
+            shards[key.shard(4)] += 1;
+        }
+        assert!(shards.iter().all(|&count| count > 800), "{:?}", shards);
+    }
+}
@@ -1,3 +1,51 @@
 // Bring in the PDU objects
 pub mod pdu;
 pub use crate::pdu::*;
+
+// Typed metrics built on top of PDUs
+pub mod metric;
+pub use crate::metric::{Metric, MetricError, MetricType, MetricValue};
+pub mod value;
+pub use crate::value::ParsedValue;
+
+// Constructing PDUs from their parts
+pub mod builder;
+pub use crate::builder::{BuildError, PduBuilder};
+
+// In-process aggregation of metrics over a flush interval
+pub mod aggregator;
+#[allow(deprecated)]
+pub use crate::aggregator::SeriesKey;
+pub use crate::aggregator::{Aggregator, Snapshot, TimerSamples};
+pub mod key;
+pub use crate::key::{MetricClass, MetricKey};
+pub mod stats;
+pub use crate::stats::{PercentileStats, TimerStats};
+pub mod sketch;
+pub use crate::sketch::{DDSketch, SketchError};
+pub mod cardinality;
+pub use crate::cardinality::{Cardinality, CardinalityError, CardinalitySet, HyperLogLog};
+mod hash;
+
+// Splitting datagrams and buffers into lines and PDUs
+pub mod split;
+pub use crate::split::{split_datagram, LineError, Lines, Messages, Pdus};
+
+// Stream framing for tokio based servers
+#[cfg(feature = "tokio")]
+pub mod codec;
+#[cfg(feature = "tokio")]
+pub use crate::codec::StatsdCodec;
+
+// DogStatsD events and service checks, and dispatching lines to the matching
+// parser
+pub mod event;
+pub use crate::event::{Event, EventAlertType, EventPriority};
+pub mod service_check;
+pub use crate::service_check::{ServiceCheck, ServiceCheckStatus};
+pub mod message;
+pub use crate::message::{Message, MessageKind};
+
+// Zero-copy tag splitting
+pub mod tags;
+pub use crate::tags::{Tag, Tags};
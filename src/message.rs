@@ -0,0 +1,89 @@
+use bytes::Bytes;
+
+use crate::event::Event;
+use crate::pdu::{ParseError, ParserConfig, PDU};
+use crate::service_check::ServiceCheck;
+
+/// The kind of message carried by a line, as determined from its prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    Metric,
+    Event,
+    ServiceCheck,
+}
+
+impl MessageKind {
+    /// Identify the kind of a line without parsing it.
+    pub fn of(line: &[u8]) -> Self {
+        if line.starts_with(b"_e{") {
+            MessageKind::Event
+        } else if line.starts_with(b"_sc|") {
+            MessageKind::ServiceCheck
+        } else {
+            MessageKind::Metric
+        }
+    }
+}
+
+/// A Message is any single line which may be received on a statsd socket,
+/// allowing one listener to handle metrics as well as DogStatsD events and
+/// service checks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Metric(PDU),
+    Event(Event),
+    ServiceCheck(ServiceCheck),
+}
+
+impl Message {
+    /// Dispatch a line to the matching parser for its kind.
+    pub fn parse(line: Bytes) -> Result<Self, ParseError> {
+        Self::parse_with(&ParserConfig::default(), line)
+    }
+
+    /// Dispatch a line to the matching parser for its kind, parsing metrics
+    /// with the given configuration.
+    pub fn parse_with(config: &ParserConfig, line: Bytes) -> Result<Self, ParseError> {
+        match MessageKind::of(&line) {
+            MessageKind::Metric => PDU::parse_with(config, line).map(Message::Metric),
+            MessageKind::Event => Event::parse(line).map(Message::Event),
+            MessageKind::ServiceCheck => ServiceCheck::parse(line).map(Message::ServiceCheck),
+        }
+    }
+
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            Message::Metric(_) => MessageKind::Metric,
+            Message::Event(_) => MessageKind::Event,
+            Message::ServiceCheck(_) => MessageKind::ServiceCheck,
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod atest {
+    use super::*;
+
+    #[test]
+    fn dispatch_messages() {
+        let message = Message::parse(Bytes::from_static(b"foo:1|c")).unwrap();
+        assert_eq!(message.kind(), MessageKind::Metric);
+        match message {
+            Message::Metric(pdu) => assert_eq!(pdu.name(), b"foo"),
+            _ => panic!("expected a metric"),
+        }
+
+        let message = Message::parse(Bytes::from_static(b"_e{1,1}:a|b|#c")).unwrap();
+        assert_eq!(message.kind(), MessageKind::Event);
+        match message {
+            Message::Event(event) => assert_eq!(event.tags().unwrap(), b"c"),
+            _ => panic!("expected an event"),
+        }
+
+        let message = Message::parse(Bytes::from_static(b"_sc|db.up|0")).unwrap();
+        assert_eq!(message.kind(), MessageKind::ServiceCheck);
+
+        // Metric names may legitimately start with an underscore
+        assert_eq!(MessageKind::of(b"_e.foo:1|c"), MessageKind::Metric);
+    }
+}
@@ -0,0 +1,372 @@
+use bytes::Bytes;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::pdu::PDU;
+use crate::value::ParsedValue;
+
+/// The well known statsd metric types, as identified by the type field of a
+/// protocol unit. Types which are not recognized are reported as `Unknown`
+/// so they can still be passed through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum MetricType {
+    Counter,
+    Gauge,
+    Timer,
+    Histogram,
+    Set,
+    Distribution,
+    Unknown,
+}
+
+impl MetricType {
+    /// Identify the metric type from the raw type field of a PDU.
+    pub fn from_bytes(pdu_type: &[u8]) -> Self {
+        match pdu_type {
+            b"c" => MetricType::Counter,
+            b"g" => MetricType::Gauge,
+            b"ms" => MetricType::Timer,
+            b"h" => MetricType::Histogram,
+            b"s" => MetricType::Set,
+            b"d" => MetricType::Distribution,
+            _ => MetricType::Unknown,
+        }
+    }
+
+    /// The canonical protocol representation of this type, if it has one.
+    pub fn as_bytes(self) -> Option<&'static [u8]> {
+        match self {
+            MetricType::Counter => Some(b"c"),
+            MetricType::Gauge => Some(b"g"),
+            MetricType::Timer => Some(b"ms"),
+            MetricType::Histogram => Some(b"h"),
+            MetricType::Set => Some(b"s"),
+            MetricType::Distribution => Some(b"d"),
+            MetricType::Unknown => None,
+        }
+    }
+}
+
+/// The parsed value of a metric. Numeric types carry their value as a float,
+/// sets carry the raw member and unknown types carry both the raw type and
+/// value fields untouched. A gauge with a signed value, such as `+5`, is a
+/// `GaugeDelta` to be added to the current value of the gauge.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetricValue {
+    Counter(f64),
+    Gauge(f64),
+    GaugeDelta(f64),
+    Timer(f64),
+    Histogram(f64),
+    Set(Bytes),
+    Distribution(f64),
+    Unknown { pdu_type: Bytes, value: Bytes },
+}
+
+impl MetricValue {
+    pub fn metric_type(&self) -> MetricType {
+        match self {
+            MetricValue::Counter(_) => MetricType::Counter,
+            MetricValue::Gauge(_) | MetricValue::GaugeDelta(_) => MetricType::Gauge,
+            MetricValue::Timer(_) => MetricType::Timer,
+            MetricValue::Histogram(_) => MetricType::Histogram,
+            MetricValue::Set(_) => MetricType::Set,
+            MetricValue::Distribution(_) => MetricType::Distribution,
+            MetricValue::Unknown { .. } => MetricType::Unknown,
+        }
+    }
+
+    /// The numeric value, for every type except sets and unknown types.
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            MetricValue::Counter(v)
+            | MetricValue::Gauge(v)
+            | MetricValue::GaugeDelta(v)
+            | MetricValue::Timer(v)
+            | MetricValue::Histogram(v)
+            | MetricValue::Distribution(v) => Some(v),
+            MetricValue::Set(_) | MetricValue::Unknown { .. } => None,
+        }
+    }
+}
+
+/// Reasons a PDU could not be interpreted as a typed `Metric`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MetricError {
+    /// The value field is not a valid number for a numeric metric type.
+    InvalidValue,
+    /// The sample rate field is not a finite, positive number.
+    InvalidSampleRate,
+    /// The timestamp field is not a valid unix timestamp.
+    InvalidTimestamp,
+}
+
+impl fmt::Display for MetricError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetricError::InvalidValue => f.write_str("invalid metric value"),
+            MetricError::InvalidSampleRate => f.write_str("invalid sample rate"),
+            MetricError::InvalidTimestamp => f.write_str("invalid timestamp"),
+        }
+    }
+}
+
+impl std::error::Error for MetricError {}
+
+/// A Metric is a fully interpreted statsd protocol unit: the value and sample
+/// rate are parsed to numbers, the tag section is split into individual tags
+/// and every tag is split into a key and optional value. The name, set
+/// members and tags share the allocation of the PDU they were built from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metric {
+    name: Bytes,
+    value: MetricValue,
+    sample_rate: Option<f64>,
+    tag_pairs: Vec<(Bytes, Option<Bytes>)>,
+    timestamp: Option<u64>,
+}
+
+impl Metric {
+    pub fn name(&self) -> &[u8] {
+        self.name.as_ref()
+    }
+
+    pub(crate) fn name_bytes(&self) -> &Bytes {
+        &self.name
+    }
+
+    pub fn value(&self) -> &MetricValue {
+        &self.value
+    }
+
+    pub fn metric_type(&self) -> MetricType {
+        self.value.metric_type()
+    }
+
+    pub fn sample_rate(&self) -> Option<f64> {
+        self.sample_rate
+    }
+
+    /// The tags of the metric in DogStatsD form, such as `env:prod`,
+    /// including any split out of the name. These are built from
+    /// `tag_pairs` on every call; prefer `tag_pairs` to avoid the copies.
+    pub fn tags(&self) -> Vec<Bytes> {
+        self.tag_pairs
+            .iter()
+            .map(|(key, value)| match value {
+                Some(value) => {
+                    let mut tag = Vec::with_capacity(key.len() + 1 + value.len());
+                    tag.extend_from_slice(key);
+                    tag.push(b':');
+                    tag.extend_from_slice(value);
+                    Bytes::from(tag)
+                }
+                None => key.clone(),
+            })
+            .collect()
+    }
+
+    /// The tags of the metric, including any split out of the name, as keys
+    /// and optional values.
+    pub fn tag_pairs(&self) -> &[(Bytes, Option<Bytes>)] {
+        &self.tag_pairs
+    }
+
+    /// The client side unix timestamp of the metric, in seconds.
+    pub fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+
+    /// Interpret a PDU as a typed metric.
+    pub fn from_pdu(pdu: &PDU) -> Result<Self, MetricError> {
+        Self::from_pdu_value(pdu, pdu.value())
+    }
+
+    /// Interpret a PDU as a typed metric with the given value, such as one of
+    /// the packed values of a multi-value PDU.
+    pub(crate) fn from_pdu_value(pdu: &PDU, value: &[u8]) -> Result<Self, MetricError> {
+        let metric_type = MetricType::from_bytes(pdu.pdu_type());
+        let parsed = ParsedValue::parse(value, metric_type)?;
+        let number = || parsed.as_f64().ok_or(MetricError::InvalidValue);
+        let value = match metric_type {
+            MetricType::Counter => MetricValue::Counter(number()?),
+            MetricType::Gauge if parsed.is_delta() => MetricValue::GaugeDelta(number()?),
+            MetricType::Gauge => MetricValue::Gauge(number()?),
+            MetricType::Timer => MetricValue::Timer(number()?),
+            MetricType::Histogram => MetricValue::Histogram(number()?),
+            MetricType::Set => MetricValue::Set(pdu.slice_ref(value)),
+            MetricType::Distribution => MetricValue::Distribution(number()?),
+            MetricType::Unknown => MetricValue::Unknown {
+                pdu_type: pdu.slice_ref(pdu.pdu_type()),
+                value: pdu.slice_ref(value),
+            },
+        };
+        let sample_rate = match pdu.sample_rate() {
+            Some(rate) => match parse::<f64>(rate) {
+                Some(rate) if rate.is_finite() && rate > 0.0 => Some(rate),
+                _ => return Err(MetricError::InvalidSampleRate),
+            },
+            None => None,
+        };
+        let timestamp = match pdu.timestamp() {
+            Some(timestamp) => Some(parse(timestamp).ok_or(MetricError::InvalidTimestamp)?),
+            None => None,
+        };
+
+        Ok(Metric {
+            name: pdu.slice_ref(pdu.name()),
+            value,
+            sample_rate,
+            tag_pairs: pdu.tag_pairs(),
+            timestamp,
+        })
+    }
+}
+
+impl TryFrom<&PDU> for Metric {
+    type Error = MetricError;
+
+    fn try_from(pdu: &PDU) -> Result<Self, Self::Error> {
+        Metric::from_pdu(pdu)
+    }
+}
+
+fn parse<T: FromStr>(buf: &[u8]) -> Option<T> {
+    std::str::from_utf8(buf).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+pub mod atest {
+    use super::*;
+    use crate::pdu::NameTagStyle;
+
+    fn metric(line: &'static [u8]) -> Result<Metric, MetricError> {
+        Metric::from_pdu(&PDU::new(Bytes::from_static(line)).unwrap())
+    }
+
+    #[test]
+    fn counter_metric() {
+        let m = metric(b"foo.bar:3|c|@0.5|#a:b,c").unwrap();
+        assert_eq!(m.name(), b"foo.bar");
+        assert_eq!(m.metric_type(), MetricType::Counter);
+        assert_eq!(m.value(), &MetricValue::Counter(3.0));
+        assert_eq!(m.sample_rate(), Some(0.5));
+        assert_eq!(m.timestamp(), None);
+        assert_eq!(
+            m.tags(),
+            vec![Bytes::from_static(b"a:b"), Bytes::from_static(b"c")]
+        );
+        assert_eq!(
+            m.tag_pairs(),
+            &[
+                (Bytes::from_static(b"a"), Some(Bytes::from_static(b"b"))),
+                (Bytes::from_static(b"c"), None)
+            ]
+        );
+    }
+
+    #[test]
+    fn name_tagged_metric() {
+        let pdu = PDU::new(Bytes::from_static(b"foo;env=prod:1|c|#a"))
+            .unwrap()
+            .split_name_tags(NameTagStyle::Graphite);
+        let m = Metric::from_pdu(&pdu).unwrap();
+        assert_eq!(m.name(), b"foo");
+        // Tags split out of the name are tags like any other
+        assert_eq!(
+            m.tags(),
+            vec![Bytes::from_static(b"env:prod"), Bytes::from_static(b"a")]
+        );
+        assert_eq!(
+            m.tag_pairs(),
+            &[
+                (
+                    Bytes::from_static(b"env"),
+                    Some(Bytes::from_static(b"prod"))
+                ),
+                (Bytes::from_static(b"a"), None)
+            ]
+        );
+
+        let pdu = PDU::new(Bytes::from_static(b"m,h=a:1|c"))
+            .unwrap()
+            .split_name_tags(NameTagStyle::Influx);
+        let m = Metric::from_pdu(&pdu).unwrap();
+        assert_eq!(m.tags(), vec![Bytes::from_static(b"h:a")]);
+        assert_eq!(m.tag_pairs().len(), 1);
+    }
+
+    #[test]
+    fn typed_metrics() {
+        assert_eq!(
+            metric(b"a:1.5|g").unwrap().value(),
+            &MetricValue::Gauge(1.5)
+        );
+        assert_eq!(
+            metric(b"a:20|ms").unwrap().value(),
+            &MetricValue::Timer(20.0)
+        );
+        assert_eq!(
+            metric(b"a:2|h").unwrap().value(),
+            &MetricValue::Histogram(2.0)
+        );
+        assert_eq!(
+            metric(b"a:2|d").unwrap().value(),
+            &MetricValue::Distribution(2.0)
+        );
+        assert_eq!(
+            metric(b"a:user1|s").unwrap().value(),
+            &MetricValue::Set(Bytes::from_static(b"user1"))
+        );
+        assert!(metric(b"a:1|c").unwrap().tags().is_empty());
+    }
+
+    #[test]
+    fn gauge_delta_metric() {
+        assert_eq!(
+            metric(b"a:-5|g").unwrap().value(),
+            &MetricValue::GaugeDelta(-5.0)
+        );
+        let m = metric(b"a:+1.5|g").unwrap();
+        assert_eq!(m.value(), &MetricValue::GaugeDelta(1.5));
+        assert_eq!(m.metric_type(), MetricType::Gauge);
+        assert_eq!(m.value().as_f64(), Some(1.5));
+        assert_eq!(
+            metric(b"a:+5|c").unwrap().value(),
+            &MetricValue::Counter(5.0)
+        );
+    }
+
+    #[test]
+    fn timestamped_metric() {
+        let m = metric(b"a:1|g|T1656581400").unwrap();
+        assert_eq!(m.value(), &MetricValue::Gauge(1.0));
+        assert_eq!(m.timestamp(), Some(1656581400));
+    }
+
+    #[test]
+    fn unknown_metric_passthrough() {
+        let m = metric(b"a:xyz|kv").unwrap();
+        assert_eq!(m.metric_type(), MetricType::Unknown);
+        assert_eq!(
+            m.value(),
+            &MetricValue::Unknown {
+                pdu_type: Bytes::from_static(b"kv"),
+                value: Bytes::from_static(b"xyz"),
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_metrics() {
+        assert_eq!(metric(b"a:abc|c"), Err(MetricError::InvalidValue));
+        assert_eq!(metric(b"a:NaN|g"), Err(MetricError::InvalidValue));
+        assert_eq!(metric(b"a:1|c|@x"), Err(MetricError::InvalidSampleRate));
+        assert_eq!(metric(b"a:1|c|@-1"), Err(MetricError::InvalidSampleRate));
+        assert_eq!(metric(b"a:1|c|@0"), Err(MetricError::InvalidSampleRate));
+        assert_eq!(metric(b"a:1|c|@NaN"), Err(MetricError::InvalidSampleRate));
+        assert_eq!(metric(b"a:1|c|@inf"), Err(MetricError::InvalidSampleRate));
+        assert_eq!(metric(b"a:1|c|T-1"), Err(MetricError::InvalidTimestamp));
+    }
+}
@@ -1,6 +1,657 @@
 use bytes::BufMut;
 use bytes::Bytes;
 use memchr::memchr;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+use crate::builder::{
+    check_name, check_sample_rate, check_tag, check_type, check_value, BuildError,
+};
+use crate::metric::{MetricError, MetricType};
+use crate::tags::{Tag, Tags};
+use crate::value::ParsedValue;
+
+/// The reason a protocol unit could not be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ParseErrorKind {
+    /// No `|` separator was found between the value and the type.
+    MissingTypeSeparator,
+    /// No `:` separator was found between the name and the value.
+    MissingValueSeparator,
+    /// More than one `|@` sample rate section was present.
+    DuplicateSampleRate,
+    /// More than one `|#` tags section was present.
+    DuplicateTags,
+    /// More than one `|T` timestamp section was present.
+    DuplicateTimestamp,
+    /// More than one `|c:` container ID section was present.
+    DuplicateContainerId,
+    /// More than one `|card:` cardinality section was present.
+    DuplicateCardinality,
+    /// A `|` section was present which is not a known protocol extension.
+    UnknownExtension,
+    /// The line exceeded the maximum allowed length.
+    LineTooLong,
+    /// An event or service check did not start with a valid header.
+    InvalidHeader,
+    /// The declared length of an event title or text does not fit the line.
+    LengthMismatch,
+    /// An optional field of an event or service check has an invalid value.
+    InvalidField,
+    /// A required field of a service check is missing.
+    MissingField,
+    /// A name, value, type or `|` section was empty.
+    EmptyField,
+    /// A metric name contained `:` where names with colons are not allowed.
+    ColonInName,
+    /// The line carried more tags than allowed.
+    TooManyTags,
+    /// The line was not valid UTF-8.
+    InvalidUtf8,
+}
+
+impl ParseErrorKind {
+    fn describe(self) -> &'static str {
+        match self {
+            ParseErrorKind::MissingTypeSeparator => "missing '|' type separator",
+            ParseErrorKind::MissingValueSeparator => "missing ':' value separator",
+            ParseErrorKind::DuplicateSampleRate => "duplicate sample rate section",
+            ParseErrorKind::DuplicateTags => "duplicate tags section",
+            ParseErrorKind::DuplicateTimestamp => "duplicate timestamp section",
+            ParseErrorKind::DuplicateContainerId => "duplicate container ID section",
+            ParseErrorKind::DuplicateCardinality => "duplicate cardinality section",
+            ParseErrorKind::UnknownExtension => "unknown extension section",
+            ParseErrorKind::LineTooLong => "line too long",
+            ParseErrorKind::InvalidHeader => "invalid header",
+            ParseErrorKind::LengthMismatch => "declared length does not match line",
+            ParseErrorKind::InvalidField => "invalid field value",
+            ParseErrorKind::MissingField => "missing required field",
+            ParseErrorKind::EmptyField => "empty field",
+            ParseErrorKind::ColonInName => "':' in metric name",
+            ParseErrorKind::TooManyTags => "too many tags",
+            ParseErrorKind::InvalidUtf8 => "invalid UTF-8",
+        }
+    }
+}
+
+/// A ParseError describes why a protocol unit was rejected, along with the
+/// byte offset into the line at which the problem was detected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ParseError {
+    kind: ParseErrorKind,
+    offset: usize,
+}
+
+impl ParseError {
+    pub fn new(kind: ParseErrorKind, offset: usize) -> Self {
+        ParseError { kind, offset }
+    }
+
+    pub fn kind(&self) -> ParseErrorKind {
+        self.kind
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {}", self.kind.describe(), self.offset)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// The marker Lyft's statsrelay and ratelimit use to identify inline tag
+/// segments in a metric name, such as `foo.__tagname=tagvalue.bar`.
+pub const DEFAULT_INLINE_TAG_MARKER: &[u8] = b"__";
+
+/// Styles of tags which may be embedded in a metric name, ahead of the `:`
+/// separating the name from the value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NameTagStyle {
+    /// Graphite 1.1 tags, such as `foo.bar;env=prod;host=a`.
+    Graphite,
+    /// InfluxDB and Telegraf tags, such as `cpu.load,host=a,region=us`.
+    Influx,
+}
+
+impl NameTagStyle {
+    /// The byte separating the name from the first tag, and tags from each
+    /// other.
+    fn separator(self) -> u8 {
+        match self {
+            NameTagStyle::Graphite => b';',
+            NameTagStyle::Influx => b',',
+        }
+    }
+
+    /// The byte separating a tag key from its value.
+    fn assignment(self) -> u8 {
+        match self {
+            NameTagStyle::Graphite | NameTagStyle::Influx => b'=',
+        }
+    }
+}
+
+/// A ParserConfig controls how strictly lines are validated when parsing a
+/// PDU with `PDU::parse_with`, and which styles of tags are recognized.
+///
+/// The default configuration matches `PDU::new`. `strict` suits edge nodes
+/// accepting metrics from untrusted clients, while `lenient` suits relays
+/// which should pass through anything resembling a metric.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParserConfig {
+    reject_empty_fields: bool,
+    max_line_length: Option<usize>,
+    max_tags: Option<usize>,
+    require_utf8: bool,
+    colons_in_names: bool,
+    multi_value: bool,
+    unknown_extensions: bool,
+    name_tag_style: Option<NameTagStyle>,
+    inline_tag_marker: Option<Vec<u8>>,
+}
+
+impl ParserConfig {
+    /// Reject empty fields and sections, and lines which are not UTF-8.
+    pub fn strict() -> Self {
+        Self::default()
+            .with_reject_empty_fields(true)
+            .with_require_utf8(true)
+    }
+
+    /// Keep unknown extensions rather than rejecting the line, as with
+    /// `PDU::new_lenient`.
+    pub fn lenient() -> Self {
+        Self::default().with_unknown_extensions(true)
+    }
+
+    /// Reject a line with an empty name, value or type, or an empty `|`
+    /// section. This includes a `|` within the last two bytes of the line,
+    /// which is otherwise taken to be part of the preceding field.
+    pub fn with_reject_empty_fields(mut self, reject: bool) -> Self {
+        self.reject_empty_fields = reject;
+        self
+    }
+
+    /// Reject lines longer than `max_line_length` bytes.
+    pub fn with_max_line_length(mut self, max_line_length: usize) -> Self {
+        self.max_line_length = Some(max_line_length);
+        self
+    }
+
+    /// Reject lines carrying more than `max_tags` tags, counting tags from
+    /// every recognized tag style.
+    pub fn with_max_tags(mut self, max_tags: usize) -> Self {
+        self.max_tags = Some(max_tags);
+        self
+    }
+
+    pub fn with_require_utf8(mut self, require: bool) -> Self {
+        self.require_utf8 = require;
+        self
+    }
+
+    /// Whether metric names may contain `:`, which is allowed by default. When
+    /// disallowed the first `:` ends the name, and any other `:` before the
+    /// type is an error.
+    pub fn with_colons_in_names(mut self, allow: bool) -> Self {
+        self.colons_in_names = allow;
+        self
+    }
+
+    /// Parse packed multi-value lines, as with `PDU::new_multi_value`.
+    pub fn with_multi_value(mut self, multi_value: bool) -> Self {
+        self.multi_value = multi_value;
+        self
+    }
+
+    /// Keep unknown `|` sections as opaque extensions rather than rejecting
+    /// the line.
+    pub fn with_unknown_extensions(mut self, keep: bool) -> Self {
+        self.unknown_extensions = keep;
+        self
+    }
+
+    /// Split tags of the given style out of metric names.
+    pub fn with_name_tags(mut self, style: NameTagStyle) -> Self {
+        self.name_tag_style = Some(style);
+        self
+    }
+
+    /// Extract Lyft-style inline tags identified by `marker` from metric
+    /// names. This rewrites any PDU which carries inline tags.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the marker is empty, as every segment containing `=` would
+    /// then be taken for a tag.
+    pub fn with_inline_tags(mut self, marker: impl Into<Vec<u8>>) -> Self {
+        let marker = marker.into();
+        assert!(!marker.is_empty(), "inline tag marker must not be empty");
+        self.inline_tag_marker = Some(marker);
+        self
+    }
+
+    pub fn max_line_length(&self) -> Option<usize> {
+        self.max_line_length
+    }
+
+    pub fn max_tags(&self) -> Option<usize> {
+        self.max_tags
+    }
+}
+
+impl Default for ParserConfig {
+    fn default() -> Self {
+        ParserConfig {
+            reject_empty_fields: false,
+            max_line_length: None,
+            max_tags: None,
+            require_utf8: false,
+            colons_in_names: true,
+            multi_value: false,
+            unknown_extensions: false,
+            name_tag_style: None,
+            inline_tag_marker: None,
+        }
+    }
+}
+
+/// The offsets of the fields of a parsed protocol unit, shared by `PDU` and
+/// `PduRef` so either can be turned into the other without parsing again.
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    value_index: usize,
+    type_index: usize,
+    type_index_end: usize,
+    sample_rate_index: Option<(usize, usize)>,
+    tags_index: Option<(usize, usize)>,
+    timestamp_index: Option<(usize, usize)>,
+    container_id_index: Option<(usize, usize)>,
+    cardinality_index: Option<(usize, usize)>,
+    name_tags: Option<(NameTagStyle, (usize, usize))>,
+    // Whether a `|` within the last two bytes of the line starts a section
+    exact_sections: bool,
+}
+
+impl Layout {
+    fn parse(line: &[u8], config: &ParserConfig) -> Result<Self, ParseError> {
+        let length = line.len();
+        let mut value_index: usize = 0;
+        // To support inner ':' symbols in a metric name (more common than you
+        // think) we'll first find the index of the first type separator, and
+        // then do a walk to find the last ':' symbol before that.
+        let type_index = memchr(b'|', line)
+            .ok_or_else(|| ParseError::new(ParseErrorKind::MissingTypeSeparator, length))?
+            + 1;
+
+        loop {
+            let value_check_index = memchr(b':', &line[value_index..type_index]);
+            match (value_check_index, value_index) {
+                (None, 0) => {
+                    return Err(ParseError::new(
+                        ParseErrorKind::MissingValueSeparator,
+                        type_index - 1,
+                    ))
+                }
+                (None, _) => break,
+                // In multi-value mode, or when names may not contain ':', the
+                // first ':' ends the name.
+                (Some(x), 0) if config.multi_value || !config.colons_in_names => {
+                    value_index = x + 1;
+                    break;
+                }
+                _ => (),
+            }
+            value_index = value_check_index.unwrap() + value_index + 1;
+        }
+        if !config.multi_value
+            && !config.colons_in_names
+            && memchr(b':', &line[value_index..type_index]).is_some()
+        {
+            return Err(ParseError::new(
+                ParseErrorKind::ColonInName,
+                value_index - 1,
+            ));
+        }
+
+        let mut sample_rate_index: Option<(usize, usize)> = None;
+        let mut tags_index: Option<(usize, usize)> = None;
+        let mut timestamp_index: Option<(usize, usize)> = None;
+        let mut container_id_index: Option<(usize, usize)> = None;
+        let mut cardinality_index: Option<(usize, usize)> = None;
+
+        let sections = if config.reject_empty_fields {
+            Sections::exact(line, type_index)
+        } else {
+            Sections::new(line, type_index)
+        };
+        let type_index_end = sections.next.unwrap_or(length);
+        if config.reject_empty_fields {
+            let empty = |offset| ParseError::new(ParseErrorKind::EmptyField, offset);
+            if value_index == 1 {
+                return Err(empty(0));
+            }
+            let mut value_start = value_index;
+            for value in line[value_index..type_index - 1].split(|b| *b == b':') {
+                if value.is_empty() {
+                    return Err(empty(value_start));
+                }
+                value_start += value.len() + 1;
+            }
+            if type_index_end == type_index {
+                return Err(empty(type_index));
+            }
+        }
+        for (index, end) in sections {
+            let section = match Section::classify(&line[index + 1..end]) {
+                Some(section) => section,
+                None if config.reject_empty_fields && end == index + 1 => {
+                    return Err(ParseError::new(ParseErrorKind::EmptyField, index))
+                }
+                None if config.unknown_extensions => continue,
+                None => return Err(ParseError::new(ParseErrorKind::UnknownExtension, index)),
+            };
+            let slot = match section {
+                Section::SampleRate => &mut sample_rate_index,
+                Section::Tags => &mut tags_index,
+                Section::Timestamp => &mut timestamp_index,
+                Section::ContainerId => &mut container_id_index,
+                Section::Cardinality => &mut cardinality_index,
+            };
+            if slot.is_some() {
+                return Err(ParseError::new(section.duplicate_error(), index));
+            }
+            let start = index + 1 + section.prefix_len();
+            if config.reject_empty_fields && start == end {
+                return Err(ParseError::new(ParseErrorKind::EmptyField, index));
+            }
+            *slot = Some((start, end));
+        }
+        Ok(Layout {
+            value_index,
+            type_index,
+            type_index_end,
+            sample_rate_index,
+            tags_index,
+            timestamp_index,
+            container_id_index,
+            cardinality_index,
+            name_tags: None,
+            exact_sections: config.reject_empty_fields,
+        })
+    }
+
+    /// Move every offset following the start of the name to a new position.
+    fn map(self, f: impl Fn(usize) -> usize) -> Self {
+        let range = |index: Option<(usize, usize)>| index.map(|(b, e)| (f(b), f(e)));
+        Layout {
+            value_index: f(self.value_index),
+            type_index: f(self.type_index),
+            type_index_end: f(self.type_index_end),
+            sample_rate_index: range(self.sample_rate_index),
+            tags_index: range(self.tags_index),
+            timestamp_index: range(self.timestamp_index),
+            container_id_index: range(self.container_id_index),
+            cardinality_index: range(self.cardinality_index),
+            name_tags: self
+                .name_tags
+                .map(|(style, index)| (style, range(Some(index)).unwrap())),
+            exact_sections: self.exact_sections,
+        }
+    }
+}
+
+/// A PduRef is a protocol unit borrowed from a plain byte slice, such as a
+/// stack buffer or a memory mapped capture, offering the same references to
+/// protocol fields as a `PDU`. Parsing a PduRef never allocates.
+///
+/// A `PDU` can be borrowed as a PduRef with `as_pdu_ref()`, and a PduRef
+/// turned into a `PDU` with `to_pdu()` or, without copying, with
+/// `to_shared_pdu()`.
+#[derive(Debug, Clone, Copy)]
+pub struct PduRef<'a> {
+    underlying: &'a [u8],
+    layout: Layout,
+}
+
+impl<'a> PduRef<'a> {
+    /// Parse a protocol unit, as with `PDU::new`.
+    pub fn new(line: &'a [u8]) -> Result<Self, ParseError> {
+        Self::parse_with(&ParserConfig::default(), line)
+    }
+
+    /// Parse a protocol unit with the given configuration. Inline tags count
+    /// towards the maximum number of tags, but are only extracted when
+    /// parsing a `PDU` as doing so rewrites the line.
+    pub fn parse_with(config: &ParserConfig, line: &'a [u8]) -> Result<Self, ParseError> {
+        if let Some(max_line_length) = config.max_line_length {
+            if line.len() > max_line_length {
+                return Err(ParseError::new(
+                    ParseErrorKind::LineTooLong,
+                    max_line_length,
+                ));
+            }
+        }
+        if config.require_utf8 {
+            std::str::from_utf8(line)
+                .map_err(|e| ParseError::new(ParseErrorKind::InvalidUtf8, e.valid_up_to()))?;
+        }
+
+        let mut pdu = PduRef {
+            underlying: line,
+            layout: Layout::parse(line, config)?,
+        };
+        if let Some(style) = config.name_tag_style {
+            pdu = pdu.split_name_tags(style);
+        }
+        if let Some(max_tags) = config.max_tags {
+            pdu.check_tag_count(max_tags, config.inline_tag_marker.as_deref())?;
+        }
+        Ok(pdu)
+    }
+
+    /// Reject the PDU if it carries more than `max_tags` tags, reporting the
+    /// offset of the first tag over the limit. Tags are counted in the order
+    /// `tag_iter()` yields them, after any inline tags.
+    fn check_tag_count(&self, max_tags: usize, marker: Option<&[u8]>) -> Result<(), ParseError> {
+        let name = self.name();
+        // Inline tags are left in a name made up only of them
+        let marker = marker.filter(|marker| !only_inline_tags(name, marker));
+        let inline_tags = marker.into_iter().flat_map(|marker| {
+            entries(name, 0, b'.')
+                .filter(move |(_, segment)| inline_tag(segment, marker).is_some())
+                .map(move |(offset, segment)| (offset + marker.len(), segment))
+        });
+        let name_tags = self
+            .layout
+            .name_tags
+            .into_iter()
+            .flat_map(|(style, (start, end))| {
+                entries(&self.underlying[start..end], start, style.separator())
+            });
+        let section_tags = self
+            .layout
+            .tags_index
+            .into_iter()
+            .flat_map(|(start, end)| entries(&self.underlying[start..end], start, b','));
+        match inline_tags
+            .chain(name_tags)
+            .chain(section_tags)
+            .nth(max_tags)
+        {
+            Some((offset, _)) => Err(ParseError::new(ParseErrorKind::TooManyTags, offset)),
+            None => Ok(()),
+        }
+    }
+
+    /// The metric name. Once name tags have been split out with
+    /// `split_name_tags`, this is the bare name without them.
+    pub fn name(&self) -> &'a [u8] {
+        match self.layout.name_tags {
+            Some((_, (start, _))) => &self.underlying[0..start - 1],
+            None => self.raw_name(),
+        }
+    }
+
+    /// The metric name as it appears in the protocol unit, including any
+    /// embedded name tags.
+    pub fn raw_name(&self) -> &'a [u8] {
+        &self.underlying[0..self.layout.value_index - 1]
+    }
+
+    /// The tags embedded in the metric name, without the leading separator,
+    /// if they have been split out with `split_name_tags`.
+    pub fn name_tags(&self) -> Option<&'a [u8]> {
+        let underlying = self.underlying;
+        self.layout.name_tags.map(|(_, v)| &underlying[v.0..v.1])
+    }
+
+    pub fn name_tag_style(&self) -> Option<NameTagStyle> {
+        self.layout.name_tags.map(|(style, _)| style)
+    }
+
+    pub fn value(&self) -> &'a [u8] {
+        &self.underlying[self.layout.value_index..self.layout.type_index - 1]
+    }
+
+    pub fn pdu_type(&self) -> &'a [u8] {
+        &self.underlying[self.layout.type_index..self.layout.type_index_end]
+    }
+
+    /// Parse the value field according to the metric type of the PDU.
+    pub fn parse_value(&self) -> Result<ParsedValue<'a>, MetricError> {
+        ParsedValue::parse(self.value(), MetricType::from_bytes(self.pdu_type()))
+    }
+
+    pub fn tags(&self) -> Option<&'a [u8]> {
+        self.section(self.layout.tags_index)
+    }
+
+    /// Iterate the individual values of the value field. Only PDUs parsed
+    /// in multi-value mode can yield more than one value.
+    pub fn values(&self) -> Values<'a> {
+        Values {
+            remaining: Some(self.value()),
+        }
+    }
+
+    /// Iterate the individual tags of the PDU: first any tags embedded in
+    /// the name, then those of the tag section.
+    pub fn tag_iter(&self) -> Tags<'a> {
+        let tags = self.tags().unwrap_or_default();
+        match self.layout.name_tags {
+            Some((style, (start, end))) => Tags::with_format(
+                &self.underlying[start..end],
+                style.separator(),
+                style.assignment(),
+                tags,
+            ),
+            None => Tags::new(tags),
+        }
+    }
+
+    pub fn sample_rate(&self) -> Option<&'a [u8]> {
+        self.section(self.layout.sample_rate_index)
+    }
+
+    /// The DogStatsD client side unix timestamp (`|T1656581400`), if any.
+    pub fn timestamp(&self) -> Option<&'a [u8]> {
+        self.section(self.layout.timestamp_index)
+    }
+
+    /// The DogStatsD origin detection container ID (`|c:<container-id>`), if
+    /// any.
+    pub fn container_id(&self) -> Option<&'a [u8]> {
+        self.section(self.layout.container_id_index)
+    }
+
+    /// The DogStatsD tag cardinality level (`|card:<level>`), if any.
+    pub fn cardinality(&self) -> Option<&'a [u8]> {
+        self.section(self.layout.cardinality_index)
+    }
+
+    /// Iterate the unknown extension sections, such as `|x:abc`, kept when
+    /// parsing leniently. Each item excludes the leading `|`.
+    pub fn extensions(&self) -> Extensions<'a> {
+        Extensions {
+            sections: Sections::with_exact(
+                self.underlying,
+                self.layout.type_index,
+                self.layout.exact_sections,
+            ),
+        }
+    }
+
+    fn section(&self, index: Option<(usize, usize)>) -> Option<&'a [u8]> {
+        let underlying = self.underlying;
+        index.map(|v| &underlying[v.0..v.1])
+    }
+
+    /// The whole protocol unit.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.underlying
+    }
+
+    pub fn len(&self) -> usize {
+        self.underlying.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.underlying.is_empty()
+    }
+
+    /// Split tags of the given style out of the metric name, as with
+    /// `PDU::split_name_tags`.
+    pub fn split_name_tags(mut self, style: NameTagStyle) -> Self {
+        let end = self.layout.value_index - 1;
+        self.layout.name_tags = memchr(style.separator(), self.raw_name())
+            .map(|index| (style, (index + 1, end)))
+            .filter(|(_, (start, end))| {
+                Tags::with_format(
+                    &self.underlying[*start..*end],
+                    style.separator(),
+                    style.assignment(),
+                    &[],
+                )
+                .all(|tag| tag.is_dogstatsd())
+            });
+        self
+    }
+
+    /// Copy the protocol unit into an owned `PDU`, keeping the parsed
+    /// offsets.
+    pub fn to_pdu(&self) -> PDU {
+        PDU {
+            underlying: Bytes::copy_from_slice(self.underlying),
+            layout: self.layout,
+        }
+    }
+
+    /// Turn the protocol unit into an owned `PDU` sharing the allocation of
+    /// `owner`, which must contain the borrowed line, without copying.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the line is not a subslice of `owner`.
+    pub fn to_shared_pdu(&self, owner: &Bytes) -> PDU {
+        PDU {
+            underlying: owner.slice_ref(self.underlying),
+            layout: self.layout,
+        }
+    }
+}
+
+impl<'a> From<PduRef<'a>> for PDU {
+    fn from(pdu: PduRef<'a>) -> Self {
+        pdu.to_pdu()
+    }
+}
 
 /// A StatsdPDU is an incoming protocol unit for statsd messages, commonly a
 /// single datagram or a line-delimitated message. This PDU type owns an
@@ -9,63 +660,376 @@ use memchr::memchr;
 #[derive(Debug, Clone)]
 pub struct PDU {
     underlying: Bytes,
-    value_index: usize,
-    type_index: usize,
-    type_index_end: usize,
-    sample_rate_index: Option<(usize, usize)>,
-    tags_index: Option<(usize, usize)>,
+    layout: Layout,
 }
 
 impl PDU {
+    /// Borrow the PDU as a `PduRef`, which is free.
+    pub fn as_pdu_ref(&self) -> PduRef<'_> {
+        PduRef {
+            underlying: &self.underlying,
+            layout: self.layout,
+        }
+    }
+
+    /// The metric name. Once name tags have been split out with
+    /// `split_name_tags`, this is the bare name without them.
     pub fn name(&self) -> &[u8] {
-        &self.underlying[0..self.value_index - 1]
+        self.as_pdu_ref().name()
+    }
+
+    /// The metric name as it appears in the protocol unit, including any
+    /// embedded name tags.
+    pub fn raw_name(&self) -> &[u8] {
+        self.as_pdu_ref().raw_name()
+    }
+
+    /// The tags embedded in the metric name, without the leading separator,
+    /// if they have been split out with `split_name_tags`.
+    pub fn name_tags(&self) -> Option<&[u8]> {
+        self.as_pdu_ref().name_tags()
+    }
+
+    pub fn name_tag_style(&self) -> Option<NameTagStyle> {
+        self.as_pdu_ref().name_tag_style()
     }
 
     pub fn value(&self) -> &[u8] {
-        &self.underlying[self.value_index..self.type_index - 1]
+        self.as_pdu_ref().value()
     }
 
     pub fn pdu_type(&self) -> &[u8] {
-        &self.underlying[self.type_index..self.type_index_end]
+        self.as_pdu_ref().pdu_type()
+    }
+
+    /// Parse the value field according to the metric type of the PDU.
+    pub fn parse_value(&self) -> Result<ParsedValue<'_>, MetricError> {
+        self.as_pdu_ref().parse_value()
     }
 
     pub fn tags(&self) -> Option<&[u8]> {
-        self.tags_index.map(|v| &self.underlying[v.0..v.1])
+        self.as_pdu_ref().tags()
+    }
+
+    /// Iterate the individual values of the value field. Only PDUs parsed
+    /// with `new_multi_value` can yield more than one value.
+    pub fn values(&self) -> Values<'_> {
+        self.as_pdu_ref().values()
+    }
+
+    /// Iterate the individual tags of the PDU: first any tags embedded in
+    /// the name, then those of the tag section.
+    pub fn tag_iter(&self) -> Tags<'_> {
+        self.as_pdu_ref().tag_iter()
     }
 
     pub fn sample_rate(&self) -> Option<&[u8]> {
-        self.sample_rate_index.map(|v| &self.underlying[v.0..v.1])
+        self.as_pdu_ref().sample_rate()
+    }
+
+    /// The DogStatsD client side unix timestamp (`|T1656581400`), if any.
+    pub fn timestamp(&self) -> Option<&[u8]> {
+        self.as_pdu_ref().timestamp()
+    }
+
+    /// The DogStatsD origin detection container ID (`|c:<container-id>`), if
+    /// any.
+    pub fn container_id(&self) -> Option<&[u8]> {
+        self.as_pdu_ref().container_id()
+    }
+
+    /// The DogStatsD tag cardinality level (`|card:<level>`), if any.
+    pub fn cardinality(&self) -> Option<&[u8]> {
+        self.as_pdu_ref().cardinality()
+    }
+
+    /// Iterate the unknown extension sections, such as `|x:abc`, kept when
+    /// parsing with `new_lenient`. Each item excludes the leading `|`.
+    pub fn extensions(&self) -> Extensions<'_> {
+        self.as_pdu_ref().extensions()
     }
 
     pub fn len(&self) -> usize {
         self.underlying.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.underlying.is_empty()
+    }
+
+    // Kept alongside the `AsRef<[u8]>` impl so existing callers relying on
+    // inference keep resolving to the raw bytes
+    #[allow(clippy::should_implement_trait)]
     pub fn as_ref(&self) -> &[u8] {
         self.underlying.as_ref()
     }
 
+    /// Return a zero-copy `Bytes` handle for a subslice of this PDU, such as
+    /// one returned by `name()` or `value()`.
+    pub(crate) fn slice_ref(&self, subset: &[u8]) -> Bytes {
+        self.underlying.slice_ref(subset)
+    }
+
+    /// The keys and values of `tag_iter()` as zero-copy `Bytes` handles.
+    pub(crate) fn tag_pairs(&self) -> Vec<(Bytes, Option<Bytes>)> {
+        self.tag_iter()
+            .map(|tag| {
+                (
+                    self.slice_ref(tag.key),
+                    tag.value.map(|v| self.slice_ref(v)),
+                )
+            })
+            .collect()
+    }
+
+    /// Split tags of the given style out of the metric name, such that
+    /// `name()` returns the bare name and the tags are yielded by
+    /// `tag_iter()`. This only records offsets and does not copy the PDU.
     ///
-    /// Return a clone of the PDU with a prefix and suffix attached to the statsd name
+    /// Name tags are only split out when every one of them can be written as
+    /// a DogStatsD tag without changing its meaning, so a key containing `:`
+    /// or `,`, or a value containing `,`, such as `foo;a:b=c`, leaves the
+    /// whole name in place.
+    pub fn split_name_tags(mut self, style: NameTagStyle) -> Self {
+        self.layout = self.as_pdu_ref().split_name_tags(style).layout;
+        self
+    }
+
+    ///
+    /// Return a clone of the PDU with a prefix and suffix attached to the statsd name.
+    /// Every other field is copied as is, unless name tags have been split out,
+    /// in which case they are moved into the DogStatsD tag section.
     ///
     pub fn with_prefix_suffix(&self, prefix: &[u8], suffix: &[u8]) -> Self {
         let offset = suffix.len() + prefix.len();
+        let name = self.name();
+
+        if self.layout.name_tags.is_some() {
+            // The suffix goes after the bare name, so the name tags have to
+            // move into the tag section
+            let mut name = Vec::with_capacity(offset + name.len());
+            name.extend_from_slice(prefix);
+            name.extend_from_slice(self.name());
+            name.extend_from_slice(suffix);
+            let mut parts = self.parts();
+            parts.name = &name;
+            return parts.build();
+        }
 
         let mut buf = bytes::BytesMut::with_capacity(self.len() + offset);
         buf.put(prefix);
-        buf.put(self.name());
+        buf.put(name);
         buf.put(suffix);
-        buf.put(self.underlying[self.value_index - 1..].as_ref());
+        buf.put(self.underlying[name.len()..].as_ref());
+
+        PDU {
+            underlying: buf.freeze(),
+            layout: self.layout.map(|index| index + offset),
+        }
+    }
+
+    /// Return a canonical form of the PDU, such that two semantically
+    /// identical protocol units produce byte-identical output. Tags, including
+    /// any split out of the name, are sorted and deduplicated into the tag
+    /// section, fields are written in
+    /// `name:value|type|@rate|#tags|T|c:|card:` order, followed by any unknown
+    /// extensions in their original order, and the default sample rate of 1
+    /// is dropped.
+    pub fn canonicalize(&self) -> Self {
+        let mut parts = self.parts();
+        parts.sample_rate = parts
+            .sample_rate
+            .filter(|rate| !is_default_sample_rate(rate));
+        parts.tags.sort_unstable();
+        parts.tags.dedup();
+        parts.build()
+    }
+
+    /// Return a clone of the PDU with any tags embedded in the name, such as
+    /// Graphite or InfluxDB style tags, moved into a DogStatsD `#` tag
+    /// section ahead of the existing tags. The name of the result is the bare
+    /// name.
+    pub fn with_dogstatsd_tags(&self) -> Self {
+        self.parts().build()
+    }
+
+    /// Return a clone of the PDU with Lyft style inline tags extracted from the
+    /// name. Every `.` separated name segment of the form
+    /// `<marker><key>=<value>` is removed from the name and added as a tag,
+    /// ahead of any existing tags. For example with the default `__` marker,
+    /// `foo.__tagname=tagvalue.bar:1|c` becomes `foo.bar:1|c|#tagname:tagvalue`.
+    ///
+    /// Every other field is left where it is, and the PDU is returned as is
+    /// when it carries no inline tags, the marker is empty or every segment
+    /// of the name is an inline tag, which would leave an empty name. Tags
+    /// which would change meaning as DogStatsD tags, such as a value
+    /// containing `,`, are left in the name.
+    pub fn extract_inline_tags(&self, marker: &[u8]) -> Self {
+        let mut name = Vec::with_capacity(self.name().len());
+        let mut inline_tags = Vec::new();
+        let mut kept_segments = 0;
+        for segment in self.name().split(|b| *b == b'.') {
+            match inline_tag(segment, marker) {
+                Some(tag) => inline_tags.push(tag),
+                None => {
+                    if kept_segments > 0 {
+                        name.push(b'.');
+                    }
+                    name.extend_from_slice(segment);
+                    kept_segments += 1;
+                }
+            }
+        }
+        if inline_tags.is_empty() || kept_segments == 0 {
+            return self.clone();
+        }
+        if self.layout.name_tags.is_some() {
+            // Name tags have to move into the tag section too
+            let mut parts = self.parts();
+            parts.name = &name;
+            inline_tags.append(&mut parts.tags);
+            parts.tags = inline_tags;
+            return parts.build();
+        }
+
+        // Splice the new name and the inline tags into the line, ahead of
+        // any existing tags or at the end of the line
+        let mut inserted = Vec::new();
+        let insert_at = match self.layout.tags_index {
+            Some((start, _)) => start,
+            None => {
+                inserted.extend_from_slice(b"|#");
+                self.len()
+            }
+        };
+        for (i, tag) in inline_tags.iter().enumerate() {
+            if i > 0 {
+                inserted.push(b',');
+            }
+            inserted.extend_from_slice(tag.key);
+            if let Some(value) = tag.value {
+                inserted.push(b':');
+                inserted.extend_from_slice(value);
+            }
+        }
+        if matches!(self.layout.tags_index, Some((start, end)) if end > start) {
+            inserted.push(b',');
+        }
 
+        let name_end = self.layout.value_index - 1;
+        let mut buf = bytes::BytesMut::with_capacity(self.len() + inserted.len());
+        buf.put(name.as_slice());
+        buf.put(&self.underlying[name_end..insert_at]);
+        buf.put(inserted.as_slice());
+        buf.put(&self.underlying[insert_at..]);
+
+        let moved = |index: usize| index - name_end + name.len();
+        let mut layout = self.layout.map(|index| {
+            if index > insert_at {
+                moved(index) + inserted.len()
+            } else {
+                moved(index)
+            }
+        });
+        layout.tags_index = Some(match self.layout.tags_index {
+            Some((start, end)) => (moved(start), moved(end) + inserted.len()),
+            None => (moved(insert_at) + 2, buf.len()),
+        });
         PDU {
             underlying: buf.freeze(),
-            value_index: self.value_index + offset,
-            type_index: self.type_index + offset,
-            type_index_end: self.type_index_end + offset,
-            sample_rate_index: self
-                .sample_rate_index
-                .map(|(b, e)| (b + offset, e + offset)),
-            tags_index: self.tags_index.map(|(b, e)| (b + offset, e + offset)),
+            layout,
+        }
+    }
+
+    /// Return a clone of the PDU with the given name. The rewriting methods
+    /// below write any tags embedded in the name into the DogStatsD tag
+    /// section, as with `with_dogstatsd_tags`. Fields are checked against the
+    /// same rules as `PduBuilder`, so the result parses back to the same
+    /// fields: names may not contain `:`, `|` or a newline.
+    pub fn with_name(&self, name: &[u8]) -> Result<Self, BuildError> {
+        check_name(name)?;
+        let mut parts = self.parts();
+        parts.name = name;
+        Ok(parts.build())
+    }
+
+    /// Return a clone of the PDU with the given value. The value may not
+    /// contain `:`, so a single value replaces any packed values.
+    pub fn with_value(&self, value: &[u8]) -> Result<Self, BuildError> {
+        check_value(value, false)?;
+        let mut parts = self.parts();
+        parts.value = value;
+        Ok(parts.build())
+    }
+
+    pub fn with_type(&self, pdu_type: &[u8]) -> Result<Self, BuildError> {
+        check_type(pdu_type)?;
+        let mut parts = self.parts();
+        parts.pdu_type = pdu_type;
+        Ok(parts.build())
+    }
+
+    /// Return a clone of the PDU with the given sample rate, such as `0.5`,
+    /// which must be a finite, positive number.
+    pub fn with_sample_rate(&self, sample_rate: &[u8]) -> Result<Self, BuildError> {
+        check_sample_rate(sample_rate)?;
+        let mut parts = self.parts();
+        parts.sample_rate = Some(sample_rate);
+        Ok(parts.build())
+    }
+
+    pub fn without_sample_rate(&self) -> Self {
+        let mut parts = self.parts();
+        parts.sample_rate = None;
+        parts.build()
+    }
+
+    /// Return a clone of the PDU with all of its tags replaced. Tags are
+    /// checked as by `PduBuilder`: keys must be non-empty and may not contain
+    /// `:` or `,`, values may not contain `,`, and neither may contain `|` or
+    /// a newline.
+    pub fn with_tags(&self, tags: &[Tag<'_>]) -> Result<Self, BuildError> {
+        tags.iter().try_for_each(check_tag)?;
+        let mut parts = self.parts();
+        parts.tags = tags.to_vec();
+        Ok(parts.build())
+    }
+
+    /// Return a clone of the PDU with the given tags added after the
+    /// existing tags, which are checked as by `with_tags`.
+    pub fn with_added_tags(&self, tags: &[Tag<'_>]) -> Result<Self, BuildError> {
+        tags.iter().try_for_each(check_tag)?;
+        let mut parts = self.parts();
+        parts.tags.extend_from_slice(tags);
+        Ok(parts.build())
+    }
+
+    /// Return a clone of the PDU without the tags for which `predicate`
+    /// returns true. The PDU is not rewritten when no tags are removed.
+    pub fn without_tags_matching<F>(&self, mut predicate: F) -> Self
+    where
+        F: FnMut(&Tag<'_>) -> bool,
+    {
+        if !self.tag_iter().any(|tag| predicate(&tag)) {
+            return self.clone();
+        }
+        let mut parts = self.parts();
+        parts.tags.retain(|tag| !predicate(tag));
+        parts.build()
+    }
+
+    /// Break the PDU into its fields, with name tags merged into the tags.
+    fn parts(&self) -> Parts<'_> {
+        Parts {
+            name: self.name(),
+            value: self.value(),
+            pdu_type: self.pdu_type(),
+            sample_rate: self.sample_rate(),
+            tags: self.tag_iter().collect(),
+            timestamp: self.timestamp(),
+            container_id: self.container_id(),
+            cardinality: self.cardinality(),
+            extensions: self.extensions().collect(),
         }
     }
 
@@ -73,70 +1037,369 @@ impl PDU {
     /// offsets for the positions and lengths of various protocol fields for
     /// later access. No parsing or validation of values is done, so at a low
     /// level this can be used to pass through unknown types and protocols.
-    pub fn new(line: Bytes) -> Option<Self> {
-        let length = line.len();
-        let mut value_index: usize = 0;
-        // To support inner ':' symbols in a metric name (more common than you
-        // think) we'll first find the index of the first type separator, and
-        // then do a walk to find the last ':' symbol before that.
-        let type_index = memchr('|' as u8, &line)? + 1;
+    pub fn new(line: Bytes) -> Result<Self, ParseError> {
+        Self::parse_with(&ParserConfig::default(), line)
+    }
 
-        loop {
-            let value_check_index = memchr(':' as u8, &line[value_index..type_index]);
-            match (value_check_index, value_index) {
-                (None, x) if x <= 0 => return None,
-                (None, _) => break,
-                _ => (),
+    /// Parse a protocol unit which may pack several values into one line, as
+    /// sent by DogStatsD protocol v1.1 clients (`name:1.5:2:3|d`). The name
+    /// ends at the first `:` so, unlike `new`, names may not contain `:`. Use
+    /// `values()` to iterate the individual values.
+    pub fn new_multi_value(line: Bytes) -> Result<Self, ParseError> {
+        Self::parse_with(&ParserConfig::default().with_multi_value(true), line)
+    }
+
+    /// Parse a protocol unit, keeping any `|` sections which are not a known
+    /// protocol extension as opaque extensions rather than rejecting the
+    /// line. Unknown extensions are available through `extensions()` and are
+    /// carried through any rewriting of the PDU, so relays can forward future
+    /// protocol additions untouched.
+    pub fn new_lenient(line: Bytes) -> Result<Self, ParseError> {
+        Self::parse_with(&ParserConfig::lenient(), line)
+    }
+
+    /// Parse a protocol unit, validating it and recognizing tags as set out
+    /// by the given configuration.
+    pub fn parse_with(config: &ParserConfig, line: Bytes) -> Result<Self, ParseError> {
+        let layout = PduRef::parse_with(config, &line)?.layout;
+        let pdu = PDU {
+            underlying: line,
+            layout,
+        };
+        match config.inline_tag_marker.as_deref() {
+            Some(marker) if config.reject_empty_fields && only_inline_tags(pdu.name(), marker) => {
+                Err(ParseError::new(ParseErrorKind::EmptyField, 0))
             }
-            value_index = value_check_index.unwrap() + value_index + 1;
+            Some(marker) => Ok(pdu.extract_inline_tags(marker)),
+            None => Ok(pdu),
         }
-        let mut type_index_end = length;
-        let mut sample_rate_index: Option<(usize, usize)> = None;
-        let mut tags_index: Option<(usize, usize)> = None;
+    }
+}
 
-        let mut scan_index = type_index;
-        loop {
-            let index = memchr('|' as u8, &line[scan_index..]).map(|v| v + scan_index);
-            match index {
-                None => break,
-                Some(x) if x + 2 >= length => break,
-                Some(x) if x < type_index_end => type_index_end = x,
-                _ => (),
-            }
-            match line[index.unwrap() + 1] {
-                b'@' => {
-                    if sample_rate_index.is_some() {
-                        return None;
-                    }
-                    sample_rate_index = index.map(|v| (v + 2, length));
-                    tags_index = tags_index.map(|(v, _l)| (v, index.unwrap()));
-                }
-                b'#' => {
-                    if tags_index.is_some() {
-                        return None;
-                    }
-                    tags_index = index.map(|v| (v + 2, length));
-                    sample_rate_index = sample_rate_index.map(|(v, _l)| (v, index.unwrap()));
+/// The fields of a PDU, from which a new PDU can be assembled with freshly
+/// computed offsets. Fields are always written in
+/// `name:value|type|@rate|#tags|T|c:|card:` order followed by any unknown
+/// extensions, and tags are always written in DogStatsD form.
+pub(crate) struct Parts<'a> {
+    pub(crate) name: &'a [u8],
+    pub(crate) value: &'a [u8],
+    pub(crate) pdu_type: &'a [u8],
+    pub(crate) sample_rate: Option<&'a [u8]>,
+    pub(crate) tags: Vec<Tag<'a>>,
+    pub(crate) timestamp: Option<&'a [u8]>,
+    pub(crate) container_id: Option<&'a [u8]>,
+    pub(crate) cardinality: Option<&'a [u8]>,
+    pub(crate) extensions: Vec<&'a [u8]>,
+}
+
+impl<'a> Parts<'a> {
+    pub(crate) fn build(&self) -> PDU {
+        let optional = [
+            self.sample_rate,
+            self.timestamp,
+            self.container_id,
+            self.cardinality,
+        ];
+        let capacity = self.name.len()
+            + self.value.len()
+            + self.pdu_type.len()
+            + optional
+                .iter()
+                .flatten()
+                .map(|v| v.len() + 6)
+                .sum::<usize>()
+            + self
+                .tags
+                .iter()
+                .map(|t| t.key.len() + t.value.map_or(0, |v| v.len() + 1) + 1)
+                .sum::<usize>()
+            + self.extensions.iter().map(|v| v.len() + 1).sum::<usize>()
+            + 4;
+
+        let mut buf = bytes::BytesMut::with_capacity(capacity);
+        buf.put(self.name);
+        buf.put_u8(b':');
+        let value_index = buf.len();
+        buf.put(self.value);
+        buf.put_u8(b'|');
+        let type_index = buf.len();
+        buf.put(self.pdu_type);
+        let type_index_end = buf.len();
+        let sample_rate_index = self.sample_rate.map(|v| put_section(&mut buf, b"|@", v));
+        let tags_index = if self.tags.is_empty() {
+            None
+        } else {
+            buf.put(b"|#".as_ref());
+            let start = buf.len();
+            for (i, tag) in self.tags.iter().enumerate() {
+                if i > 0 {
+                    buf.put_u8(b',');
                 }
-                _ => return None,
+                put_tag(&mut buf, tag);
             }
-            scan_index = index.unwrap() + 1;
+            Some((start, buf.len()))
+        };
+        let timestamp_index = self.timestamp.map(|v| put_section(&mut buf, b"|T", v));
+        let container_id_index = self.container_id.map(|v| put_section(&mut buf, b"|c:", v));
+        let cardinality_index = self
+            .cardinality
+            .map(|v| put_section(&mut buf, b"|card:", v));
+        for extension in &self.extensions {
+            put_section(&mut buf, b"|", extension);
         }
-        Some(PDU {
-            underlying: line,
-            value_index,
-            type_index,
-            type_index_end,
-            sample_rate_index: sample_rate_index,
-            tags_index: tags_index,
+
+        PDU {
+            underlying: buf.freeze(),
+            layout: Layout {
+                value_index,
+                type_index,
+                type_index_end,
+                sample_rate_index,
+                tags_index,
+                timestamp_index,
+                container_id_index,
+                cardinality_index,
+                name_tags: None,
+                // Only a short extension needs exact scanning to be found,
+                // which would otherwise be read as part of the field before
+                exact_sections: self.extensions.iter().any(|v| v.len() < 2),
+            },
+        }
+    }
+}
+
+/// The known `|` delimited sections which may follow the type field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Section {
+    SampleRate,
+    Tags,
+    Timestamp,
+    ContainerId,
+    Cardinality,
+}
+
+impl Section {
+    /// Identify a section from its contents following the leading `|`.
+    pub(crate) fn classify(section: &[u8]) -> Option<Self> {
+        match section {
+            [b'@', ..] => Some(Section::SampleRate),
+            [b'#', ..] => Some(Section::Tags),
+            [b'T', ..] => Some(Section::Timestamp),
+            [b'c', b':', ..] => Some(Section::ContainerId),
+            [b'c', b'a', b'r', b'd', b':', ..] => Some(Section::Cardinality),
+            _ => None,
+        }
+    }
+
+    /// The length of the marker identifying the section, such as `@`.
+    fn prefix_len(self) -> usize {
+        match self {
+            Section::SampleRate | Section::Tags | Section::Timestamp => 1,
+            Section::ContainerId => 2,
+            Section::Cardinality => 5,
+        }
+    }
+
+    fn duplicate_error(self) -> ParseErrorKind {
+        match self {
+            Section::SampleRate => ParseErrorKind::DuplicateSampleRate,
+            Section::Tags => ParseErrorKind::DuplicateTags,
+            Section::Timestamp => ParseErrorKind::DuplicateTimestamp,
+            Section::ContainerId => ParseErrorKind::DuplicateContainerId,
+            Section::Cardinality => ParseErrorKind::DuplicateCardinality,
+        }
+    }
+}
+
+/// Iterates the `|` delimited sections following the type field of a line,
+/// yielding the index of each section's leading `|` and the end of the
+/// section. A `|` within the last two bytes of the line does not start a new
+/// section.
+#[derive(Debug, Clone)]
+struct Sections<'a> {
+    line: &'a [u8],
+    next: Option<usize>,
+    exact: bool,
+}
+
+impl<'a> Sections<'a> {
+    fn new(line: &'a [u8], from: usize) -> Self {
+        Self::with_exact(line, from, false)
+    }
+
+    /// Iterate every section, including one starting within the last two
+    /// bytes of the line.
+    fn exact(line: &'a [u8], from: usize) -> Self {
+        Self::with_exact(line, from, true)
+    }
+
+    fn with_exact(line: &'a [u8], from: usize, exact: bool) -> Self {
+        Sections {
+            line,
+            next: Self::find(line, from, exact),
+            exact,
+        }
+    }
+
+    fn find(line: &[u8], from: usize, exact: bool) -> Option<usize> {
+        memchr(b'|', &line[from..])
+            .map(|index| index + from)
+            .filter(|index| exact || index + 2 < line.len())
+    }
+}
+
+impl<'a> Iterator for Sections<'a> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        let index = self.next?;
+        self.next = Self::find(self.line, index + 1, self.exact);
+        Some((index, self.next.unwrap_or(self.line.len())))
+    }
+}
+
+/// Iterate the non-empty `separator` delimited entries of a region of a line
+/// starting at offset `base`, along with the offset of each entry.
+fn entries(region: &[u8], base: usize, separator: u8) -> impl Iterator<Item = (usize, &[u8])> {
+    let mut offset = base;
+    region
+        .split(move |b| *b == separator)
+        .filter_map(move |entry| {
+            let start = offset;
+            offset += entry.len() + 1;
+            Some((start, entry)).filter(|_| !entry.is_empty())
         })
+}
+
+/// Split a `<marker>key=value` segment of a metric name into an inline tag.
+fn inline_tag<'a>(segment: &'a [u8], marker: &[u8]) -> Option<Tag<'a>> {
+    if marker.is_empty() {
+        return None;
+    }
+    let tag = segment.strip_prefix(marker)?;
+    let index = memchr(b'=', tag)?;
+    // Leave tags which would change meaning as DogStatsD tags in the name
+    Some(Tag::new(&tag[..index], Some(&tag[index + 1..]))).filter(Tag::is_dogstatsd)
+}
+
+/// Whether every `.` separated segment of a name is an inline tag, such that
+/// extracting them would leave an empty name.
+fn only_inline_tags(name: &[u8], marker: &[u8]) -> bool {
+    name.split(|b| *b == b'.')
+        .all(|segment| inline_tag(segment, marker).is_some())
+}
+
+/// Write a `|` prefixed section to the buffer, returning the offsets of the
+/// section's value.
+fn put_section(buf: &mut bytes::BytesMut, prefix: &[u8], value: &[u8]) -> (usize, usize) {
+    buf.put(prefix);
+    let start = buf.len();
+    buf.put(value);
+    (start, buf.len())
+}
+
+/// Write a tag in DogStatsD `key:value` form.
+fn put_tag(buf: &mut bytes::BytesMut, tag: &Tag) {
+    buf.put(tag.key);
+    if let Some(value) = tag.value {
+        buf.put_u8(b':');
+        buf.put(value);
     }
 }
 
+/// An iterator over the unknown extension sections of a PDU parsed with
+/// `new_lenient`, yielding each section's contents without the leading `|`.
+#[derive(Debug, Clone)]
+pub struct Extensions<'a> {
+    sections: Sections<'a>,
+}
+
+impl<'a> Iterator for Extensions<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let line = self.sections.line;
+        self.sections
+            .by_ref()
+            .map(|(index, end)| &line[index + 1..end])
+            .find(|section| Section::classify(section).is_none())
+    }
+}
+
+/// An iterator over the `:` separated values of a multi-value PDU.
+#[derive(Debug, Clone)]
+pub struct Values<'a> {
+    remaining: Option<&'a [u8]>,
+}
+
+impl<'a> Iterator for Values<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let remaining = self.remaining?;
+        match memchr(b':', remaining) {
+            Some(index) => {
+                self.remaining = Some(&remaining[index + 1..]);
+                Some(&remaining[..index])
+            }
+            None => self.remaining.take(),
+        }
+    }
+}
+
+impl AsRef<[u8]> for PDU {
+    fn as_ref(&self) -> &[u8] {
+        self.underlying.as_ref()
+    }
+}
+
+/// PDUs compare and hash by their raw bytes. Use `canonicalize()` first to
+/// compare protocol units by meaning rather than by representation.
+impl PartialEq for PDU {
+    fn eq(&self, other: &Self) -> bool {
+        self.underlying == other.underlying
+    }
+}
+
+impl Eq for PDU {}
+
+impl Hash for PDU {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.underlying.hash(state)
+    }
+}
+
+impl<'a> AsRef<[u8]> for PduRef<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.underlying
+    }
+}
+
+impl<'a> PartialEq for PduRef<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.underlying == other.underlying
+    }
+}
+
+impl<'a> Eq for PduRef<'a> {}
+
+impl<'a> Hash for PduRef<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.underlying.hash(state)
+    }
+}
+
+fn is_default_sample_rate(rate: &[u8]) -> bool {
+    std::str::from_utf8(rate)
+        .ok()
+        .and_then(|rate| rate.parse::<f64>().ok())
+        == Some(1.0)
+}
+
 #[cfg(test)]
 pub mod atest {
     use super::*;
-    use anyhow::anyhow;
+    use crate::tags::Tag;
 
     #[test]
     fn parse_pdus() -> anyhow::Result<()> {
@@ -148,7 +1411,7 @@ pub mod atest {
         ];
         for buf in valid {
             println!("{}", String::from_utf8(buf.clone())?);
-            PDU::new(buf.into()).ok_or(anyhow!("no pdu"))?;
+            PDU::new(buf.into())?;
         }
         Ok(())
     }
@@ -161,6 +1424,35 @@ pub mod atest {
         assert_eq!(pdu.pdu_type(), b"c")
     }
 
+    #[test]
+    fn parse_pdu_value() {
+        let pdu = PDU::new(Bytes::from_static(b"foo:-2|g")).unwrap();
+        assert_eq!(pdu.parse_value(), Ok(ParsedValue::Delta(-2.0)));
+        let pdu = PDU::new(Bytes::from_static(b"foo:-2|c")).unwrap();
+        assert_eq!(pdu.parse_value(), Ok(ParsedValue::Integer(-2)));
+    }
+
+    #[test]
+    fn multi_value_pdu() {
+        let line = Bytes::from_static(b"name:1.5:2:3|d|#a:b");
+        let pdu = PDU::new_multi_value(line.clone()).unwrap();
+        assert_eq!(pdu.name(), b"name");
+        assert_eq!(pdu.value(), b"1.5:2:3");
+        assert_eq!(pdu.pdu_type(), b"d");
+        assert_eq!(pdu.tags().unwrap(), b"a:b");
+        let values: Vec<&[u8]> = pdu.values().collect();
+        assert_eq!(values, vec![&b"1.5"[..], b"2", b"3"]);
+
+        // The default mode keeps the colons as part of the name
+        let pdu = PDU::new(line).unwrap();
+        assert_eq!(pdu.name(), b"name:1.5:2");
+        assert_eq!(pdu.values().collect::<Vec<_>>(), vec![&b"3"[..]]);
+
+        let pdu = PDU::new_multi_value(Bytes::from_static(b"name:4|c")).unwrap();
+        assert_eq!(pdu.values().collect::<Vec<_>>(), vec![&b"4"[..]]);
+        assert!(PDU::new_multi_value(Bytes::from_static(b"name|c")).is_err());
+    }
+
     #[test]
     fn tagged_pdu() {
         let pdu = PDU::new(Bytes::from_static(b"foo.bar:3|c|@1.0|#tags")).unwrap();
@@ -171,6 +1463,235 @@ pub mod atest {
         assert_eq!(pdu.sample_rate().unwrap(), b"1.0");
     }
 
+    #[test]
+    fn tag_iter_pdu() {
+        let pdu = PDU::new(Bytes::from_static(b"foo.bar:3|c|#a:b,,c")).unwrap();
+        let tags: Vec<Tag> = pdu.tag_iter().collect();
+        assert_eq!(tags, vec![Tag::new(b"a", Some(b"b")), Tag::new(b"c", None)]);
+        let pdu = PDU::new(Bytes::from_static(b"foo.bar:3|c")).unwrap();
+        assert_eq!(pdu.tag_iter().count(), 0);
+    }
+
+    #[test]
+    fn timestamp_pdu() {
+        let pdu = PDU::new(Bytes::from_static(b"foo.bar:3|c|T1656581400|#a:b|@0.5")).unwrap();
+        assert_eq!(pdu.pdu_type(), b"c");
+        assert_eq!(pdu.timestamp().unwrap(), b"1656581400");
+        assert_eq!(pdu.tags().unwrap(), b"a:b");
+        assert_eq!(pdu.sample_rate().unwrap(), b"0.5");
+
+        let pdu = pdu.with_prefix_suffix(b"pre.", b"");
+        assert_eq!(pdu.name(), b"pre.foo.bar");
+        assert_eq!(pdu.timestamp().unwrap(), b"1656581400");
+        assert_eq!(
+            pdu.canonicalize().as_ref(),
+            b"pre.foo.bar:3|c|@0.5|#a:b|T1656581400"
+        );
+
+        let err = PDU::new(Bytes::from_static(b"foo:3|c|T1|T2")).unwrap_err();
+        assert_eq!(err.kind(), ParseErrorKind::DuplicateTimestamp);
+        assert_eq!(err.offset(), 10);
+    }
+
+    #[test]
+    fn origin_detection_pdu() {
+        let opdu = PDU::new(Bytes::from_static(
+            b"foo.bar:3|c|card:orchestrator|#a:b|c:abc123|@0.5",
+        ))
+        .unwrap();
+        assert_eq!(opdu.pdu_type(), b"c");
+        assert_eq!(opdu.container_id().unwrap(), b"abc123");
+        assert_eq!(opdu.cardinality().unwrap(), b"orchestrator");
+        assert_eq!(opdu.tags().unwrap(), b"a:b");
+        assert_eq!(opdu.sample_rate().unwrap(), b"0.5");
+
+        let pdu = opdu.with_prefix_suffix(b"pre.", b"");
+        assert_eq!(pdu.container_id().unwrap(), b"abc123");
+        assert_eq!(pdu.cardinality().unwrap(), b"orchestrator");
+        let pdu = opdu.canonicalize();
+        assert_eq!(
+            pdu.as_ref(),
+            b"foo.bar:3|c|@0.5|#a:b|c:abc123|card:orchestrator"
+        );
+        assert_eq!(pdu.container_id().unwrap(), b"abc123");
+        assert_eq!(pdu.cardinality().unwrap(), b"orchestrator");
+
+        let err = PDU::new(Bytes::from_static(b"foo:3|c|c:a|c:b")).unwrap_err();
+        assert_eq!(err.kind(), ParseErrorKind::DuplicateContainerId);
+        let err = PDU::new(Bytes::from_static(b"foo:3|c|cardinal")).unwrap_err();
+        assert_eq!(err.kind(), ParseErrorKind::UnknownExtension);
+    }
+
+    #[test]
+    fn extension_pdu() {
+        let line = Bytes::from_static(b"foo.bar:3|c|x:abc|#a:b|future|@0.5");
+        assert_eq!(
+            PDU::new(line.clone()).unwrap_err().kind(),
+            ParseErrorKind::UnknownExtension
+        );
+        let opdu = PDU::new_lenient(line).unwrap();
+        assert_eq!(opdu.pdu_type(), b"c");
+        assert_eq!(opdu.tags().unwrap(), b"a:b");
+        assert_eq!(opdu.sample_rate().unwrap(), b"0.5");
+        let extensions: Vec<&[u8]> = opdu.extensions().collect();
+        assert_eq!(extensions, vec![&b"x:abc"[..], b"future"]);
+
+        let pdu = opdu.with_prefix_suffix(b"pre.", b"");
+        assert_eq!(pdu.as_ref(), b"pre.foo.bar:3|c|x:abc|#a:b|future|@0.5");
+        assert_eq!(pdu.extensions().count(), 2);
+        let pdu = opdu.canonicalize();
+        assert_eq!(pdu.as_ref(), b"foo.bar:3|c|@0.5|#a:b|x:abc|future");
+        assert_eq!(pdu.extensions().count(), 2);
+
+        let pdu = PDU::new(Bytes::from_static(b"foo:1|c|#a")).unwrap();
+        assert_eq!(pdu.extensions().count(), 0);
+    }
+
+    #[test]
+    fn graphite_name_tags() {
+        let opdu = PDU::new(Bytes::from_static(b"foo.bar;env=prod;host=a:3|c|#x:y")).unwrap();
+        assert_eq!(opdu.name(), b"foo.bar;env=prod;host=a");
+        assert_eq!(opdu.name_tags(), None);
+
+        let pdu = opdu.clone().split_name_tags(NameTagStyle::Graphite);
+        assert_eq!(pdu.name(), b"foo.bar");
+        assert_eq!(pdu.raw_name(), b"foo.bar;env=prod;host=a");
+        assert_eq!(pdu.name_tags().unwrap(), b"env=prod;host=a");
+        assert_eq!(pdu.name_tag_style(), Some(NameTagStyle::Graphite));
+        assert_eq!(pdu.value(), b"3");
+        let tags: Vec<Tag> = pdu.tag_iter().collect();
+        assert_eq!(
+            tags,
+            vec![
+                Tag::new(b"env", Some(b"prod")),
+                Tag::new(b"host", Some(b"a")),
+                Tag::new(b"x", Some(b"y")),
+            ]
+        );
+
+        let prefixed = pdu.with_prefix_suffix(b"pre.", b".suf");
+        assert_eq!(
+            prefixed.as_ref(),
+            b"pre.foo.bar.suf:3|c|#env:prod,host:a,x:y"
+        );
+        assert_eq!(prefixed.name(), b"pre.foo.bar.suf");
+        assert_eq!(prefixed.name_tags(), None);
+
+        assert_eq!(
+            pdu.canonicalize().as_ref(),
+            b"foo.bar:3|c|#env:prod,host:a,x:y"
+        );
+        assert_eq!(
+            pdu.canonicalize(),
+            PDU::new(Bytes::from_static(b"foo.bar:3|c|#x:y,host:a,env:prod"))
+                .unwrap()
+                .canonicalize()
+        );
+
+        let pdu = PDU::new(Bytes::from_static(b"foo.bar:3|c"))
+            .unwrap()
+            .split_name_tags(NameTagStyle::Graphite);
+        assert_eq!(pdu.name(), b"foo.bar");
+        assert_eq!(pdu.name_tags(), None);
+
+        // Tags which would change meaning as DogStatsD tags stay in the name
+        for line in &[&b"foo;a:b=c:1|c"[..], b"foo;env=prod;a=b,c:1|c"] {
+            let opdu = PDU::new(Bytes::copy_from_slice(line)).unwrap();
+            let pdu = opdu.clone().split_name_tags(NameTagStyle::Graphite);
+            assert_eq!(pdu.name_tags(), None);
+            assert_eq!(pdu.name(), opdu.name());
+            assert_eq!(pdu.canonicalize().as_ref(), *line);
+        }
+    }
+
+    #[test]
+    fn influx_name_tags() {
+        let pdu = PDU::new(Bytes::from_static(
+            b"cpu.load,host=a,region=us:3|g|@0.5|#x:y",
+        ))
+        .unwrap()
+        .split_name_tags(NameTagStyle::Influx);
+        assert_eq!(pdu.name(), b"cpu.load");
+        assert_eq!(pdu.name_tags().unwrap(), b"host=a,region=us");
+        let tags: Vec<Tag> = pdu.tag_iter().collect();
+        assert_eq!(
+            tags,
+            vec![
+                Tag::new(b"host", Some(b"a")),
+                Tag::new(b"region", Some(b"us")),
+                Tag::new(b"x", Some(b"y")),
+            ]
+        );
+
+        let dogstatsd = pdu.with_dogstatsd_tags();
+        assert_eq!(
+            dogstatsd.as_ref(),
+            b"cpu.load:3|g|@0.5|#host:a,region:us,x:y"
+        );
+        assert_eq!(dogstatsd.name(), b"cpu.load");
+        assert_eq!(dogstatsd.name_tags(), None);
+        assert_eq!(dogstatsd.sample_rate().unwrap(), b"0.5");
+        assert_eq!(dogstatsd.tags().unwrap(), b"host:a,region:us,x:y");
+    }
+
+    #[test]
+    fn inline_tags() {
+        let opdu = PDU::new(Bytes::from_static(
+            b"foo.__tagname=tagvalue.bar.__b=2:1|c|#x:y",
+        ))
+        .unwrap();
+        let pdu = opdu.extract_inline_tags(DEFAULT_INLINE_TAG_MARKER);
+        assert_eq!(pdu.as_ref(), b"foo.bar:1|c|#tagname:tagvalue,b:2,x:y");
+        assert_eq!(pdu.name(), b"foo.bar");
+        assert_eq!(pdu.value(), b"1");
+        let tags: Vec<Tag> = pdu.tag_iter().collect();
+        assert_eq!(tags[0], Tag::new(b"tagname", Some(b"tagvalue")));
+
+        let pdu = PDU::new(Bytes::from_static(b"__a=1.foo.tag_b=2.__bare:1|ms"))
+            .unwrap()
+            .extract_inline_tags(b"tag_");
+        assert_eq!(pdu.as_ref(), b"__a=1.foo.__bare:1|ms|#b:2");
+
+        let pdu = PDU::new(Bytes::from_static(b"foo.bar:1|c")).unwrap();
+        assert_eq!(pdu.extract_inline_tags(DEFAULT_INLINE_TAG_MARKER), pdu);
+        let pdu = PDU::new(Bytes::from_static(b"foo.a=1:1|c")).unwrap();
+        assert_eq!(pdu.extract_inline_tags(b""), pdu);
+        // A tag value containing ',' would become two DogStatsD tags
+        let pdu = PDU::new(Bytes::from_static(b"foo.__a=1,2:1|c")).unwrap();
+        assert_eq!(pdu.extract_inline_tags(DEFAULT_INLINE_TAG_MARKER), pdu);
+        // Extracting every segment would leave no name
+        let pdu = PDU::new(Bytes::from_static(b"__a=1.__b=2:1|c")).unwrap();
+        assert_eq!(pdu.extract_inline_tags(DEFAULT_INLINE_TAG_MARKER), pdu);
+
+        // Other fields keep their place and still parse back the same
+        let lines: Vec<&'static [u8]> = vec![
+            b"x.__a=1:1|c|#b|@0.5|T10",
+            b"x.__a=1:1|c|@0.5|#|T10",
+            b"x.__a=1:1|c|T10|@0.5",
+            b"x.__a=1:1|c|c:abc",
+        ];
+        let expected: Vec<&'static [u8]> = vec![
+            b"x:1|c|#a:1,b|@0.5|T10",
+            b"x:1|c|@0.5|#a:1|T10",
+            b"x:1|c|T10|@0.5|#a:1",
+            b"x:1|c|c:abc|#a:1",
+        ];
+        for (line, expected) in lines.into_iter().zip(expected) {
+            let pdu = PDU::new(Bytes::from_static(line))
+                .unwrap()
+                .extract_inline_tags(DEFAULT_INLINE_TAG_MARKER);
+            assert_eq!(pdu.as_ref(), expected);
+            let parsed = PDU::new(Bytes::from_static(expected)).unwrap();
+            assert_eq!(pdu.name(), parsed.name());
+            assert_eq!(pdu.value(), parsed.value());
+            assert_eq!(pdu.pdu_type(), parsed.pdu_type());
+            assert_eq!(pdu.sample_rate(), parsed.sample_rate());
+            assert_eq!(pdu.tags(), parsed.tags());
+            assert_eq!(pdu.timestamp(), parsed.timestamp());
+            assert_eq!(pdu.container_id(), parsed.container_id());
+        }
+    }
+
     #[test]
     fn tagged_pdu_reverse() {
         let pdu = PDU::new(Bytes::from_static(b"foo.bar:3|c|#tags|@1.0")).unwrap();
@@ -181,6 +1702,256 @@ pub mod atest {
         assert_eq!(pdu.sample_rate().unwrap(), b"1.0");
     }
 
+    #[test]
+    fn parse_errors() {
+        let invalid: Vec<(&'static [u8], ParseErrorKind, usize)> = vec![
+            (b"foo.bar:3", ParseErrorKind::MissingTypeSeparator, 9),
+            (b"foo.bar|c", ParseErrorKind::MissingValueSeparator, 7),
+            (b"foo:3|c|@1|@0.5", ParseErrorKind::DuplicateSampleRate, 10),
+            (b"foo:3|c|#a|@1|#b", ParseErrorKind::DuplicateTags, 13),
+            (b"foo:3|c|x:abc", ParseErrorKind::UnknownExtension, 7),
+        ];
+        for (line, kind, offset) in invalid {
+            let err = PDU::new(Bytes::from_static(line)).unwrap_err();
+            assert_eq!(err.kind(), kind);
+            assert_eq!(err.offset(), offset);
+        }
+    }
+
+    #[test]
+    fn strict_parse_errors() {
+        let strict = ParserConfig::strict();
+        let invalid: Vec<(&'static [u8], ParseErrorKind, usize)> = vec![
+            (b":3|c", ParseErrorKind::EmptyField, 0),
+            (b"foo:|c", ParseErrorKind::EmptyField, 4),
+            (b"foo:3|", ParseErrorKind::EmptyField, 6),
+            (b"foo:3|c|", ParseErrorKind::EmptyField, 7),
+            (b"foo:3|c|@", ParseErrorKind::EmptyField, 7),
+            (b"foo:3|c||#a", ParseErrorKind::EmptyField, 7),
+            (b"foo:3|c|x", ParseErrorKind::UnknownExtension, 7),
+            (b"f\xffo:3|c", ParseErrorKind::InvalidUtf8, 1),
+        ];
+        for (line, kind, offset) in invalid {
+            let err = PDU::parse_with(&strict, Bytes::from_static(line)).unwrap_err();
+            assert_eq!(err.kind(), kind, "{}", String::from_utf8_lossy(line));
+            assert_eq!(err.offset(), offset);
+        }
+        // The default configuration keeps the historic leniency
+        for line in [&b"foo:3|c|"[..], b"foo:3|c|@", b"foo:3|c|x"] {
+            PDU::new(Bytes::from_static(line)).unwrap();
+        }
+
+        let pdu = PDU::parse_with(&strict, Bytes::from_static(b"foo:3|c|@0.5|#a")).unwrap();
+        assert_eq!(pdu.sample_rate().unwrap(), b"0.5");
+
+        let multi = strict.clone().with_multi_value(true);
+        let err = PDU::parse_with(&multi, Bytes::from_static(b"foo:1::2|d")).unwrap_err();
+        assert_eq!(err.kind(), ParseErrorKind::EmptyField);
+        assert_eq!(err.offset(), 6);
+
+        // A short unknown section is only part of the type when lenient
+        let lenient = ParserConfig::lenient();
+        let pdu = PDU::parse_with(&lenient, Bytes::from_static(b"foo:3|c|x")).unwrap();
+        assert_eq!(pdu.pdu_type(), b"c|x");
+        let both = strict.with_unknown_extensions(true);
+        let pdu = PDU::parse_with(&both, Bytes::from_static(b"foo:3|c|x")).unwrap();
+        assert_eq!(pdu.pdu_type(), b"c");
+        assert_eq!(pdu.extensions().collect::<Vec<_>>(), vec![b"x"]);
+        let canonical = pdu.canonicalize();
+        assert_eq!(canonical.as_ref(), b"foo:3|c|x");
+        assert_eq!(canonical.pdu_type(), b"c");
+        assert_eq!(canonical.extensions().collect::<Vec<_>>(), vec![b"x"]);
+        let pdu = PDU::parse_with(&both, Bytes::from_static(b"foo:3|c|xy")).unwrap();
+        assert_eq!(pdu.extensions().collect::<Vec<_>>(), vec![b"xy"]);
+    }
+
+    #[test]
+    fn parser_config_limits() {
+        let config = ParserConfig::default()
+            .with_max_line_length(12)
+            .with_max_tags(2)
+            .with_colons_in_names(false);
+        let parse = |line: &'static [u8]| PDU::parse_with(&config, Bytes::from_static(line));
+        assert_eq!(parse(b"foo:3|c|#a,b").unwrap().tag_iter().count(), 2);
+
+        let err = parse(b"foo:3|c|#a,bc").unwrap_err();
+        assert_eq!(err.kind(), ParseErrorKind::LineTooLong);
+        assert_eq!(err.offset(), 12);
+        let err = parse(b"f:3|c|#a,b,c").unwrap_err();
+        assert_eq!(err.kind(), ParseErrorKind::TooManyTags);
+        assert_eq!(err.offset(), 11);
+        let err = parse(b"a:b:3|c").unwrap_err();
+        assert_eq!(err.kind(), ParseErrorKind::ColonInName);
+        assert_eq!(err.offset(), 1);
+
+        // Tags in the name count towards the limit
+        let config = ParserConfig::default()
+            .with_max_tags(2)
+            .with_name_tags(NameTagStyle::Graphite)
+            .with_inline_tags(DEFAULT_INLINE_TAG_MARKER);
+        let parse = |line: &'static [u8]| PDU::parse_with(&config, Bytes::from_static(line));
+        let pdu = parse(b"foo.__a=1;b=2:3|c").unwrap();
+        assert_eq!(pdu.as_ref(), b"foo:3|c|#a:1,b:2");
+        let err = parse(b"foo.__a=1;b=2:3|c|#c").unwrap_err();
+        assert_eq!(err.kind(), ParseErrorKind::TooManyTags);
+        assert_eq!(err.offset(), 19);
+        let err = parse(b"x.__a=1.__b=2.__c=3:3|c").unwrap_err();
+        assert_eq!(err.offset(), 16);
+
+        // A name made up only of inline tags is kept whole, or rejected as
+        // empty once extracted in strict mode
+        let line = Bytes::from_static(b"__a=1.__b=2:3|c");
+        let config = ParserConfig::default().with_inline_tags(DEFAULT_INLINE_TAG_MARKER);
+        let pdu = PDU::parse_with(&config, line.clone()).unwrap();
+        assert_eq!(pdu.as_ref(), line.as_ref());
+        assert_eq!(pdu.name(), b"__a=1.__b=2");
+        let config = ParserConfig::strict().with_inline_tags(DEFAULT_INLINE_TAG_MARKER);
+        let err = PDU::parse_with(&config, line).unwrap_err();
+        assert_eq!(err.kind(), ParseErrorKind::EmptyField);
+
+        let config = ParserConfig::default()
+            .with_max_tags(2)
+            .with_name_tags(NameTagStyle::Influx);
+        let err = PDU::parse_with(&config, Bytes::from_static(b"m,a=1,,b=2,c=3:1|c")).unwrap_err();
+        assert_eq!(err.kind(), ParseErrorKind::TooManyTags);
+        assert_eq!(err.offset(), 11);
+    }
+
+    #[test]
+    fn borrowed_pdu() {
+        let line: [u8; 23] = *b"foo.bar:3|c|@0.5|#a:b,c";
+        let pdu = PduRef::new(&line).unwrap();
+        assert_eq!(pdu.name(), b"foo.bar");
+        assert_eq!(pdu.value(), b"3");
+        assert_eq!(pdu.pdu_type(), b"c");
+        assert_eq!(pdu.sample_rate().unwrap(), b"0.5");
+        assert_eq!(pdu.tag_iter().count(), 2);
+        assert_eq!(pdu.as_bytes(), &line[..]);
+
+        let owned = pdu.to_pdu();
+        assert_eq!(owned.as_ref(), pdu.as_ref());
+        assert_eq!(owned.tags(), pdu.tags());
+        assert_eq!(owned.as_pdu_ref(), pdu);
+
+        // Sharing the allocation of a buffer the line was borrowed from
+        let buf = Bytes::from_static(b"a:1|c\nb;env=prod:2|g");
+        let config = ParserConfig::default().with_name_tags(NameTagStyle::Graphite);
+        let borrowed = PduRef::parse_with(&config, &buf[6..]).unwrap();
+        let shared = borrowed.to_shared_pdu(&buf);
+        assert_eq!(shared.as_ref().as_ptr(), buf[6..].as_ptr());
+        assert_eq!(shared.name(), b"b");
+        assert_eq!(shared.name_tags().unwrap(), b"env=prod");
+
+        let err = PduRef::parse_with(&ParserConfig::strict(), b"foo:|c").unwrap_err();
+        assert_eq!(err.kind(), ParseErrorKind::EmptyField);
+    }
+
+    #[test]
+    fn canonical_pdu() {
+        let forward = PDU::new(Bytes::from_static(b"foo.bar:3|c|@1.0|#b:2,a:1,b:2"))
+            .unwrap()
+            .canonicalize();
+        let reverse = PDU::new(Bytes::from_static(b"foo.bar:3|c|#a:1,,b:2|@1"))
+            .unwrap()
+            .canonicalize();
+        assert_eq!(forward.as_ref(), b"foo.bar:3|c|#a:1,b:2");
+        assert_eq!(forward, reverse);
+        assert_eq!(reverse.tags().unwrap(), b"a:1,b:2");
+        assert_eq!(reverse.sample_rate(), None);
+
+        let hash = |pdu: &PDU| {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            pdu.hash(&mut hasher);
+            hasher.finish()
+        };
+        assert_eq!(hash(&forward), hash(&reverse));
+    }
+
+    #[test]
+    fn canonical_pdu_sample_rate() {
+        let pdu = PDU::new(Bytes::from_static(b"foo:3|c|#|@0.5"))
+            .unwrap()
+            .canonicalize();
+        assert_eq!(pdu.as_ref(), b"foo:3|c|@0.5");
+        assert_eq!(pdu.sample_rate().unwrap(), b"0.5");
+        assert_eq!(pdu.pdu_type(), b"c");
+        assert_eq!(pdu.tags(), None);
+    }
+
+    #[test]
+    fn rewrite_fields() {
+        let pdu = PDU::new_lenient(Bytes::from_static(b"foo;env=prod:3|c|@0.5|#a:1|x:y"))
+            .unwrap()
+            .split_name_tags(NameTagStyle::Graphite);
+
+        let renamed = pdu.with_name(b"bar").unwrap();
+        assert_eq!(renamed.as_ref(), b"bar:3|c|@0.5|#env:prod,a:1|x:y");
+        assert_eq!(renamed.name(), b"bar");
+        assert_eq!(renamed.extensions().collect::<Vec<_>>(), vec![b"x:y"]);
+
+        let pdu = pdu.with_dogstatsd_tags();
+        assert_eq!(pdu.with_value(b"42").unwrap().value(), b"42");
+        assert_eq!(pdu.with_type(b"g").unwrap().pdu_type(), b"g");
+        assert_eq!(
+            pdu.with_sample_rate(b"0.1").unwrap().as_ref(),
+            b"foo:3|c|@0.1|#env:prod,a:1|x:y"
+        );
+        assert_eq!(pdu.with_name(b"a:b"), Err(BuildError::InvalidName));
+        assert_eq!(pdu.with_name(b"a|b"), Err(BuildError::InvalidName));
+        assert_eq!(pdu.with_value(b"1:2"), Err(BuildError::InvalidValue));
+        assert_eq!(pdu.with_value(b""), Err(BuildError::InvalidValue));
+        assert_eq!(pdu.with_type(b"c\n"), Err(BuildError::InvalidType));
+        assert_eq!(
+            pdu.with_sample_rate(b""),
+            Err(BuildError::InvalidSampleRate)
+        );
+        assert_eq!(
+            pdu.with_sample_rate(b"0.1|#x"),
+            Err(BuildError::InvalidSampleRate)
+        );
+        assert_eq!(
+            pdu.with_sample_rate(b"-1"),
+            Err(BuildError::InvalidSampleRate)
+        );
+        let unsampled = pdu.without_sample_rate();
+        assert_eq!(unsampled.as_ref(), b"foo:3|c|#env:prod,a:1|x:y");
+        assert_eq!(unsampled.tags().unwrap(), b"env:prod,a:1");
+    }
+
+    #[test]
+    fn rewrite_tags() {
+        let pdu = PDU::new(Bytes::from_static(b"foo:3|c|#a:1,_internal:x,b")).unwrap();
+        let global = [Tag::new(b"dc", Some(b"us-1"))];
+
+        assert_eq!(
+            pdu.with_tags(&global).unwrap().as_ref(),
+            b"foo:3|c|#dc:us-1"
+        );
+        assert_eq!(pdu.with_tags(&[]).unwrap().as_ref(), b"foo:3|c");
+        assert_eq!(
+            pdu.with_added_tags(&global).unwrap().as_ref(),
+            b"foo:3|c|#a:1,_internal:x,b,dc:us-1"
+        );
+        let invalid = [
+            Tag::new(b"a", Some(b"1|@0.5")),
+            Tag::new(b"a,b", None),
+            Tag::new(b"a:b", Some(b"c")),
+            Tag::new(b"a", Some(b"1,2")),
+            Tag::new(b"", Some(b"1")),
+            Tag::new(b"a\n", None),
+        ];
+        for tag in &invalid {
+            assert_eq!(pdu.with_tags(&[*tag]), Err(BuildError::InvalidTag));
+            assert_eq!(pdu.with_added_tags(&[*tag]), Err(BuildError::InvalidTag));
+        }
+
+        let stripped = pdu.without_tags_matching(|tag| tag.key.starts_with(b"_"));
+        assert_eq!(stripped.as_ref(), b"foo:3|c|#a:1,b");
+        assert_eq!(stripped.tag_iter().count(), 2);
+        let untouched = pdu.without_tags_matching(|tag| tag.key == b"missing");
+        assert_eq!(untouched.as_ref().as_ptr(), pdu.as_ref().as_ptr());
+    }
+
     #[test]
     fn prefix_suffix_test() {
         let opdu = PDU::new(Bytes::from_static(b"foo.bar:3|c|#tags|@1.0")).unwrap();
@@ -190,5 +1961,16 @@ pub mod atest {
         assert_eq!(pdu.pdu_type(), b"c");
         assert_eq!(pdu.tags().unwrap(), b"tags");
         assert_eq!(pdu.sample_rate().unwrap(), b"1.0");
+
+        // Every other field keeps its place and still parses back the same
+        let opdu = PDU::new(Bytes::from_static(b"foo:1|c|#a|@0.5")).unwrap();
+        let pdu = opdu.with_prefix_suffix(b"p.", b"");
+        assert_eq!(pdu.as_ref(), b"p.foo:1|c|#a|@0.5");
+        assert_eq!(pdu.tags().unwrap(), b"a");
+        assert_eq!(pdu.sample_rate().unwrap(), b"0.5");
+        assert_eq!(
+            pdu,
+            PDU::new(Bytes::from_static(b"p.foo:1|c|#a|@0.5")).unwrap()
+        );
     }
 }
@@ -0,0 +1,312 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use memchr::memchr;
+
+use crate::builder::{has_reserved, BuildError};
+use crate::event::{parse_decimal, unescape_newlines};
+use crate::pdu::{ParseError, ParseErrorKind};
+use crate::tags::Tags;
+
+/// The status of a DogStatsD service check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ServiceCheckStatus {
+    Ok,
+    Warning,
+    Critical,
+    Unknown,
+}
+
+impl ServiceCheckStatus {
+    pub fn from_bytes(status: &[u8]) -> Option<Self> {
+        match status {
+            b"0" => Some(ServiceCheckStatus::Ok),
+            b"1" => Some(ServiceCheckStatus::Warning),
+            b"2" => Some(ServiceCheckStatus::Critical),
+            b"3" => Some(ServiceCheckStatus::Unknown),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(self) -> &'static [u8] {
+        match self {
+            ServiceCheckStatus::Ok => b"0",
+            ServiceCheckStatus::Warning => b"1",
+            ServiceCheckStatus::Critical => b"2",
+            ServiceCheckStatus::Unknown => b"3",
+        }
+    }
+}
+
+/// A ServiceCheck is a DogStatsD service check message of the form
+/// `_sc|<name>|<status>|d:<timestamp>|h:<hostname>|#<tags>|m:<message>`
+/// where every field following the status is optional. The message must be
+/// the last field, and runs to the end of the line so it may contain `|`.
+///
+/// Service checks can be parsed from a line, or constructed from parts and
+/// written back out with `encode`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceCheck {
+    name: Bytes,
+    status: ServiceCheckStatus,
+    timestamp: Option<u64>,
+    hostname: Option<Bytes>,
+    tags: Option<Bytes>,
+    message: Option<Bytes>,
+}
+
+impl ServiceCheck {
+    pub fn new(name: impl Into<Bytes>, status: ServiceCheckStatus) -> Self {
+        ServiceCheck {
+            name: name.into(),
+            status,
+            timestamp: None,
+            hostname: None,
+            tags: None,
+            message: None,
+        }
+    }
+
+    /// Set the unix timestamp of the check, in seconds.
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    pub fn with_hostname(mut self, hostname: impl Into<Bytes>) -> Self {
+        self.hostname = Some(hostname.into());
+        self
+    }
+
+    /// Set the tag section, such as `a:b,c`, without the leading `#`.
+    pub fn with_tags(mut self, tags: impl Into<Bytes>) -> Self {
+        self.tags = Some(tags.into());
+        self
+    }
+
+    pub fn with_message(mut self, message: impl Into<Bytes>) -> Self {
+        self.message = Some(message.into());
+        self
+    }
+
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    pub fn status(&self) -> ServiceCheckStatus {
+        self.status
+    }
+
+    /// The unix timestamp of the check (`|d:`), in seconds.
+    pub fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+
+    pub fn hostname(&self) -> Option<&[u8]> {
+        self.hostname.as_deref()
+    }
+
+    pub fn tags(&self) -> Option<&[u8]> {
+        self.tags.as_deref()
+    }
+
+    /// Iterate the individual tags of the tag section, if any.
+    pub fn tag_iter(&self) -> Tags<'_> {
+        self.tags().map_or_else(Tags::empty, Tags::new)
+    }
+
+    /// The message of the check, with escaped newlines unescaped.
+    pub fn message(&self) -> Option<&[u8]> {
+        self.message.as_deref()
+    }
+
+    /// Parse a single service check line.
+    pub fn parse(line: Bytes) -> Result<Self, ParseError> {
+        let length = line.len();
+        if !line.starts_with(b"_sc|") {
+            return Err(ParseError::new(ParseErrorKind::InvalidHeader, 0));
+        }
+        let name_start = 4;
+        let name_end = memchr(b'|', &line[name_start..]).map_or(length, |v| v + name_start);
+        if name_end == name_start {
+            return Err(ParseError::new(ParseErrorKind::MissingField, name_start));
+        }
+        let status_start = name_end + 1;
+        if status_start >= length {
+            return Err(ParseError::new(ParseErrorKind::MissingField, length));
+        }
+        let status_end = memchr(b'|', &line[status_start..]).map_or(length, |v| v + status_start);
+        let status = ServiceCheckStatus::from_bytes(&line[status_start..status_end])
+            .ok_or_else(|| ParseError::new(ParseErrorKind::InvalidField, status_start))?;
+
+        let mut check = ServiceCheck::new(line.slice(name_start..name_end), status);
+        let mut index = status_end;
+        while index < length {
+            let start = index + 1;
+            if line[start..].starts_with(b"m:") {
+                // The message always comes last, and may itself contain '|'
+                check.message = Some(unescape_newlines(line.slice(start + 2..)));
+                break;
+            }
+            let end = memchr(b'|', &line[start..]).map_or(length, |v| v + start);
+            match &line[start..end] {
+                [b'd', b':', v @ ..] => {
+                    let timestamp = parse_decimal(v)
+                        .ok_or_else(|| ParseError::new(ParseErrorKind::InvalidField, start))?;
+                    check.timestamp = Some(timestamp);
+                }
+                [b'h', b':', ..] => check.hostname = Some(line.slice(start + 2..end)),
+                [b'#', ..] => check.tags = Some(line.slice(start + 1..end)),
+                _ => return Err(ParseError::new(ParseErrorKind::UnknownExtension, index)),
+            }
+            index = end;
+        }
+        Ok(check)
+    }
+
+    /// Write the service check out as a single line, without a trailing
+    /// newline. Newlines in the message are escaped, while the name, hostname
+    /// and tags may not contain `|` or a newline, so the line parses back to
+    /// the same check.
+    pub fn encode(&self) -> Result<Bytes, BuildError> {
+        if self.name.is_empty() || has_reserved(&self.name, b"|\n") {
+            return Err(BuildError::InvalidName);
+        }
+        if matches!(&self.hostname, Some(hostname) if has_reserved(hostname, b"|\n")) {
+            return Err(BuildError::InvalidHostname);
+        }
+        if matches!(&self.tags, Some(tags) if has_reserved(tags, b"|\n")) {
+            return Err(BuildError::InvalidTag);
+        }
+        let mut buf = BytesMut::with_capacity(
+            16 + self.name.len()
+                + self.hostname.as_ref().map_or(0, Bytes::len)
+                + self.tags.as_ref().map_or(0, Bytes::len)
+                + self.message.as_ref().map_or(0, Bytes::len),
+        );
+        buf.put(b"_sc|".as_ref());
+        buf.put(self.name.as_ref());
+        buf.put_u8(b'|');
+        buf.put(self.status.as_bytes());
+        if let Some(timestamp) = self.timestamp {
+            buf.put(b"|d:".as_ref());
+            buf.put(itoa::Buffer::new().format(timestamp).as_bytes());
+        }
+        if let Some(hostname) = &self.hostname {
+            buf.put(b"|h:".as_ref());
+            buf.put(hostname.as_ref());
+        }
+        if let Some(tags) = &self.tags {
+            buf.put(b"|#".as_ref());
+            buf.put(tags.as_ref());
+        }
+        if let Some(message) = &self.message {
+            buf.put(b"|m:".as_ref());
+            put_escaped_newlines(&mut buf, message);
+        }
+        Ok(buf.freeze())
+    }
+}
+
+/// Write a field with newlines escaped as `\n`.
+fn put_escaped_newlines(buf: &mut BytesMut, mut field: &[u8]) {
+    while let Some(index) = memchr(b'\n', field) {
+        buf.put(&field[..index]);
+        buf.put(b"\\n".as_ref());
+        field = &field[index + 1..];
+    }
+    buf.put(field);
+}
+
+#[cfg(test)]
+pub mod atest {
+    use super::*;
+    use crate::tags::Tag;
+
+    #[test]
+    fn simple_service_check() {
+        let check = ServiceCheck::parse(Bytes::from_static(b"_sc|db.up|2")).unwrap();
+        assert_eq!(check.name(), b"db.up");
+        assert_eq!(check.status(), ServiceCheckStatus::Critical);
+        assert_eq!(check.timestamp(), None);
+        assert_eq!(check.hostname(), None);
+        assert_eq!(check.message(), None);
+    }
+
+    #[test]
+    fn full_service_check() {
+        let check = ServiceCheck::parse(Bytes::from_static(
+            b"_sc|db.up|1|d:123|h:host|#a:b,c|m:slow | retrying\\nsoon",
+        ))
+        .unwrap();
+        assert_eq!(check.status(), ServiceCheckStatus::Warning);
+        assert_eq!(check.timestamp(), Some(123));
+        assert_eq!(check.hostname().unwrap(), b"host");
+        let tags: Vec<Tag> = check.tag_iter().collect();
+        assert_eq!(tags, vec![Tag::new(b"a", Some(b"b")), Tag::new(b"c", None)]);
+        assert_eq!(check.message().unwrap(), b"slow | retrying\nsoon");
+    }
+
+    #[test]
+    fn encode_service_check() {
+        let check = ServiceCheck::new(&b"db.up"[..], ServiceCheckStatus::Ok)
+            .with_timestamp(123)
+            .with_hostname(&b"host"[..])
+            .with_tags(&b"a:b"[..])
+            .with_message(&b"all good\nreally"[..]);
+        let line = check.encode().unwrap();
+        assert_eq!(
+            line.as_ref(),
+            b"_sc|db.up|0|d:123|h:host|#a:b|m:all good\\nreally"
+        );
+
+        let parsed = ServiceCheck::parse(line).unwrap();
+        assert_eq!(parsed.message().unwrap(), b"all good\nreally");
+        assert_eq!(parsed.name(), check.name());
+        assert_eq!(parsed.tags(), check.tags());
+        assert_eq!(
+            ServiceCheck::new(&b"a"[..], ServiceCheckStatus::Unknown)
+                .encode()
+                .unwrap()
+                .as_ref(),
+            b"_sc|a|3"
+        );
+    }
+
+    #[test]
+    fn encode_invalid_service_checks() {
+        let check = |name: &'static [u8]| ServiceCheck::new(name, ServiceCheckStatus::Ok);
+        assert_eq!(check(b"a|b").encode(), Err(BuildError::InvalidName));
+        assert_eq!(check(b"a\nb").encode(), Err(BuildError::InvalidName));
+        assert_eq!(check(b"").encode(), Err(BuildError::InvalidName));
+        assert_eq!(
+            check(b"a").with_hostname(&b"h|x"[..]).encode(),
+            Err(BuildError::InvalidHostname)
+        );
+        assert_eq!(
+            check(b"a").with_tags(&b"a:b\nc"[..]).encode(),
+            Err(BuildError::InvalidTag)
+        );
+        // The message runs to the end of the line, so it may contain '|'
+        let line = check(b"a").with_message(&b"x|y"[..]).encode().unwrap();
+        assert_eq!(
+            ServiceCheck::parse(line).unwrap().message().unwrap(),
+            b"x|y"
+        );
+    }
+
+    #[test]
+    fn invalid_service_checks() {
+        let invalid: Vec<(&'static [u8], ParseErrorKind)> = vec![
+            (b"_sc:db.up|0", ParseErrorKind::InvalidHeader),
+            (b"_sc|db.up", ParseErrorKind::MissingField),
+            (b"_sc||0", ParseErrorKind::MissingField),
+            (b"_sc|db.up|", ParseErrorKind::MissingField),
+            (b"_sc|db.up|5", ParseErrorKind::InvalidField),
+            (b"_sc|db.up|0|d:x", ParseErrorKind::InvalidField),
+            (b"_sc|db.up|0|x:y", ParseErrorKind::UnknownExtension),
+        ];
+        for (line, kind) in invalid {
+            let err = ServiceCheck::parse(Bytes::from_static(line)).unwrap_err();
+            assert_eq!(err.kind(), kind, "{}", String::from_utf8_lossy(line));
+        }
+    }
+}
@@ -0,0 +1,453 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The reason a sketch could not be decoded or merged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum SketchError {
+    /// The encoded sketch was truncated, of an unknown version or otherwise
+    /// malformed.
+    InvalidEncoding,
+    /// The sketches were built with different parameters, such as a
+    /// different relative accuracy, and cannot be merged.
+    IncompatibleSketches,
+    /// The relative accuracy was not between 0 and 1, exclusive.
+    InvalidAccuracy,
+}
+
+impl fmt::Display for SketchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SketchError::InvalidEncoding => f.write_str("invalid sketch encoding"),
+            SketchError::IncompatibleSketches => f.write_str("incompatible sketches"),
+            SketchError::InvalidAccuracy => f.write_str("invalid relative accuracy"),
+        }
+    }
+}
+
+impl std::error::Error for SketchError {}
+
+const DDSKETCH_VERSION: u8 = 1;
+
+/// A DDSketch is a mergeable quantile sketch with a relative error guarantee:
+/// every quantile it reports is within `relative_accuracy` of the true value
+/// at that rank, whatever the distribution of the values. Values are counted
+/// in logarithmically sized buckets, so memory grows with the range of the
+/// values rather than their number.
+///
+/// Sketches built with the same relative accuracy, such as on separate
+/// shards, can be merged, and sketches can be encoded to bytes and back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DDSketch {
+    relative_accuracy: f64,
+    // The natural logarithm of the bucket growth factor gamma
+    gamma_ln: f64,
+    positive: BTreeMap<i32, f64>,
+    negative: BTreeMap<i32, f64>,
+    zero_count: f64,
+    count: f64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl DDSketch {
+    /// The smallest magnitude kept in a bucket of its own. Smaller values are
+    /// counted as zero.
+    const MIN_INDEXABLE: f64 = 1e-9;
+
+    /// Create an empty sketch with the given relative accuracy, such as `0.01`
+    /// for quantiles within 1% of their true values.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the relative accuracy is not between 0 and 1, exclusive.
+    pub fn new(relative_accuracy: f64) -> Self {
+        Self::try_new(relative_accuracy).expect("relative accuracy must be between 0 and 1")
+    }
+
+    /// Create an empty sketch with the given relative accuracy, or
+    /// `SketchError::InvalidAccuracy` if it is not between 0 and 1, exclusive.
+    pub fn try_new(relative_accuracy: f64) -> Result<Self, SketchError> {
+        validate_accuracy(relative_accuracy)?;
+        let gamma = (1.0 + relative_accuracy) / (1.0 - relative_accuracy);
+        Ok(DDSketch {
+            relative_accuracy,
+            gamma_ln: gamma.ln(),
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            zero_count: 0.0,
+            count: 0.0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        })
+    }
+
+    pub fn relative_accuracy(&self) -> f64 {
+        self.relative_accuracy
+    }
+
+    pub fn add(&mut self, value: f64) {
+        self.add_weighted(value, 1.0);
+    }
+
+    /// Add a value standing for `weight` values, such as a sample sent with a
+    /// sample rate of `1 / weight`. Non-finite values and weights are ignored.
+    pub fn add_weighted(&mut self, value: f64, weight: f64) {
+        if !value.is_finite() || !weight.is_finite() || weight <= 0.0 {
+            return;
+        }
+        if value >= Self::MIN_INDEXABLE {
+            *self.positive.entry(self.index(value)).or_default() += weight;
+        } else if value <= -Self::MIN_INDEXABLE {
+            *self.negative.entry(self.index(-value)).or_default() += weight;
+        } else {
+            self.zero_count += weight;
+        }
+        self.count += weight;
+        self.sum += value * weight;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    /// Merge another sketch into this one, as if every value added to it had
+    /// been added to this sketch.
+    pub fn merge(&mut self, other: &DDSketch) -> Result<(), SketchError> {
+        if other.relative_accuracy != self.relative_accuracy {
+            return Err(SketchError::IncompatibleSketches);
+        }
+        for (index, count) in &other.positive {
+            *self.positive.entry(*index).or_default() += count;
+        }
+        for (index, count) in &other.negative {
+            *self.negative.entry(*index).or_default() += count;
+        }
+        self.zero_count += other.zero_count;
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        Ok(())
+    }
+
+    /// The value at quantile `q`, between 0 and 1, or `None` if the sketch is
+    /// empty.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count <= 0.0 || !(0.0..=1.0).contains(&q) {
+            return None;
+        }
+        let rank = q * (self.count - 1.0);
+        let mut seen = 0.0;
+        // Values in ascending order: negative values from the largest
+        // magnitude down, then zero, then positive values
+        for (index, count) in self.negative.iter().rev() {
+            seen += count;
+            if seen > rank {
+                return Some(self.clamp(-self.value(*index)));
+            }
+        }
+        seen += self.zero_count;
+        if seen > rank {
+            return Some(self.clamp(0.0));
+        }
+        for (index, count) in &self.positive {
+            seen += count;
+            if seen > rank {
+                return Some(self.clamp(self.value(*index)));
+            }
+        }
+        Some(self.max)
+    }
+
+    /// The number of values added, accounting for their weights.
+    pub fn count(&self) -> f64 {
+        self.count
+    }
+
+    /// The sum of the values added, accounting for their weights.
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    pub fn min(&self) -> Option<f64> {
+        self.unless_empty(self.min)
+    }
+
+    pub fn max(&self) -> Option<f64> {
+        self.unless_empty(self.max)
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        self.unless_empty(self.sum / self.count)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count <= 0.0
+    }
+
+    /// Encode the sketch, such that `decode` returns an identical sketch.
+    pub fn encode(&self) -> Bytes {
+        let bins = self.positive.len() + self.negative.len();
+        let mut buf = BytesMut::with_capacity(1 + 8 * 6 + 8 + bins * 12);
+        buf.put_u8(DDSKETCH_VERSION);
+        for v in &[
+            self.relative_accuracy,
+            self.zero_count,
+            self.count,
+            self.sum,
+            self.min,
+            self.max,
+        ] {
+            buf.put_f64_le(*v);
+        }
+        for store in &[&self.positive, &self.negative] {
+            buf.put_u32_le(store.len() as u32);
+            for (index, count) in store.iter() {
+                buf.put_i32_le(*index);
+                buf.put_f64_le(*count);
+            }
+        }
+        buf.freeze()
+    }
+
+    /// Decode a sketch written by `encode`. Counts must be finite and not
+    /// negative, every bin index must appear once per store, and the bins
+    /// must add up to the total count.
+    pub fn decode(mut buf: &[u8]) -> Result<Self, SketchError> {
+        let invalid = SketchError::InvalidEncoding;
+        if buf.remaining() < 1 + 8 * 6 || buf.get_u8() != DDSKETCH_VERSION {
+            return Err(invalid);
+        }
+        let mut sketch = DDSketch::try_new(buf.get_f64_le()).map_err(|_| invalid)?;
+        sketch.zero_count = buf.get_f64_le();
+        sketch.count = buf.get_f64_le();
+        sketch.sum = buf.get_f64_le();
+        sketch.min = buf.get_f64_le();
+        sketch.max = buf.get_f64_le();
+        let valid_count = |count: f64| count.is_finite() && count >= 0.0;
+        if !valid_count(sketch.zero_count) || !valid_count(sketch.count) {
+            return Err(invalid);
+        }
+        let mut total = sketch.zero_count;
+        for store in &mut [&mut sketch.positive, &mut sketch.negative] {
+            if buf.remaining() < 4 {
+                return Err(invalid);
+            }
+            let bins = buf.get_u32_le() as usize;
+            if buf.remaining() < bins.saturating_mul(12) {
+                return Err(invalid);
+            }
+            for _ in 0..bins {
+                let index = buf.get_i32_le();
+                let count = buf.get_f64_le();
+                if !valid_count(count) || store.insert(index, count).is_some() {
+                    return Err(invalid);
+                }
+                total += count;
+            }
+        }
+        // Allow for rounding, as merging adds up the bins in a different
+        // order than the count
+        if buf.has_remaining() || (total - sketch.count).abs() > sketch.count * 1e-9 {
+            return Err(invalid);
+        }
+        Ok(sketch)
+    }
+
+    fn index(&self, magnitude: f64) -> i32 {
+        (magnitude.ln() / self.gamma_ln).ceil() as i32
+    }
+
+    /// The value representing a bucket, within the relative accuracy of every
+    /// value in it.
+    fn value(&self, index: i32) -> f64 {
+        let gamma = self.gamma_ln.exp();
+        2.0 * (self.gamma_ln * f64::from(index)).exp() / (gamma + 1.0)
+    }
+
+    fn clamp(&self, value: f64) -> f64 {
+        value.max(self.min).min(self.max)
+    }
+
+    fn unless_empty(&self, value: f64) -> Option<f64> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(value)
+        }
+    }
+}
+
+/// Check a relative accuracy is between 0 and 1, exclusive.
+pub(crate) fn validate_accuracy(relative_accuracy: f64) -> Result<(), SketchError> {
+    if relative_accuracy > 0.0 && relative_accuracy < 1.0 {
+        Ok(())
+    } else {
+        Err(SketchError::InvalidAccuracy)
+    }
+}
+
+#[cfg(test)]
+pub mod atest {
+    use super::*;
+
+    fn assert_within(actual: f64, expected: f64, accuracy: f64) {
+        assert!(
+            (actual - expected).abs() <= expected.abs() * accuracy,
+            "{} not within {} of {}",
+            actual,
+            accuracy,
+            expected
+        );
+    }
+
+    #[test]
+    fn sketch_quantiles() {
+        let mut sketch = DDSketch::new(0.01);
+        for v in 1..=1000 {
+            sketch.add(f64::from(v));
+        }
+        assert_eq!(sketch.count(), 1000.0);
+        assert_eq!(sketch.sum(), 500500.0);
+        assert_eq!(sketch.min(), Some(1.0));
+        assert_eq!(sketch.max(), Some(1000.0));
+        assert_eq!(sketch.quantile(0.0), Some(1.0));
+        assert_eq!(sketch.quantile(1.0), Some(1000.0));
+        for (q, expected) in &[(0.5, 500.0), (0.9, 900.0), (0.99, 990.0)] {
+            assert_within(sketch.quantile(*q).unwrap(), *expected, 0.01);
+        }
+        assert_eq!(sketch.quantile(1.5), None);
+        assert_eq!(DDSketch::new(0.01).quantile(0.5), None);
+        assert_eq!(DDSketch::new(0.01).mean(), None);
+        assert_eq!(DDSketch::try_new(0.0), Err(SketchError::InvalidAccuracy));
+        assert_eq!(
+            DDSketch::try_new(f64::NAN),
+            Err(SketchError::InvalidAccuracy)
+        );
+        assert!(DDSketch::try_new(0.5).is_ok());
+    }
+
+    #[test]
+    fn sketch_negative_and_weighted() {
+        let mut sketch = DDSketch::new(0.02);
+        for v in -50..=50 {
+            sketch.add(f64::from(v));
+        }
+        assert_within(sketch.quantile(0.25).unwrap(), -25.0, 0.02);
+        assert_eq!(sketch.quantile(0.5), Some(0.0));
+        assert_within(sketch.quantile(0.75).unwrap(), 25.0, 0.02);
+
+        let mut sketch = DDSketch::new(0.02);
+        sketch.add_weighted(10.0, 9.0);
+        sketch.add_weighted(100.0, 1.0);
+        sketch.add_weighted(f64::NAN, 1.0);
+        assert_eq!(sketch.count(), 10.0);
+        assert_eq!(sketch.sum(), 190.0);
+        assert_within(sketch.quantile(0.5).unwrap(), 10.0, 0.02);
+        assert_eq!(sketch.quantile(1.0), Some(100.0));
+    }
+
+    #[test]
+    fn merge_sketches() {
+        let mut left = DDSketch::new(0.01);
+        let mut right = DDSketch::new(0.01);
+        let mut whole = DDSketch::new(0.01);
+        for v in 1..=500 {
+            left.add(f64::from(v));
+            right.add(f64::from(v + 500));
+        }
+        for v in 1..=1000 {
+            whole.add(f64::from(v));
+        }
+        left.merge(&right).unwrap();
+        assert_eq!(left.count(), whole.count());
+        assert_eq!(left.quantile(0.9), whole.quantile(0.9));
+        assert_eq!(left.min(), Some(1.0));
+        assert_eq!(left.max(), Some(1000.0));
+
+        assert_eq!(
+            left.merge(&DDSketch::new(0.02)),
+            Err(SketchError::IncompatibleSketches)
+        );
+    }
+
+    #[test]
+    fn encode_sketch() {
+        let mut sketch = DDSketch::new(0.01);
+        for v in &[-3.5, 0.0, 1.0, 2.5, 1e6] {
+            sketch.add(*v);
+        }
+        let encoded = sketch.encode();
+        assert_eq!(DDSketch::decode(&encoded), Ok(sketch));
+        assert_eq!(
+            DDSketch::decode(&encoded[..encoded.len() - 1]),
+            Err(SketchError::InvalidEncoding)
+        );
+        assert_eq!(DDSketch::decode(b"\x02"), Err(SketchError::InvalidEncoding));
+        let empty = DDSketch::new(0.05);
+        assert_eq!(DDSketch::decode(&empty.encode()), Ok(empty));
+    }
+
+    #[test]
+    fn decode_invalid_sketches() {
+        let mut sketch = DDSketch::new(0.01);
+        sketch.add(0.0);
+        sketch.add_weighted(2.0, 3.0);
+        // version, accuracy, zero count, count, sum, min, max, then the
+        // positive store of one bin and the empty negative store
+        let encoded = sketch.encode().to_vec();
+        assert_eq!(encoded.len(), 1 + 8 * 6 + 4 + 12 + 4);
+        let with = |offset: usize, bytes: &[u8]| {
+            let mut buf = encoded.clone();
+            buf[offset..offset + bytes.len()].copy_from_slice(bytes);
+            DDSketch::decode(&buf)
+        };
+        let (zero_count, count, bin_count) = (9, 17, 1 + 8 * 6 + 4 + 4);
+        assert_eq!(
+            with(zero_count, &f64::NAN.to_le_bytes()),
+            Err(SketchError::InvalidEncoding)
+        );
+        assert_eq!(
+            with(count, &(-4.0f64).to_le_bytes()),
+            Err(SketchError::InvalidEncoding)
+        );
+        assert_eq!(
+            with(bin_count, &(-3.0f64).to_le_bytes()),
+            Err(SketchError::InvalidEncoding)
+        );
+        assert_eq!(
+            with(bin_count, &f64::INFINITY.to_le_bytes()),
+            Err(SketchError::InvalidEncoding)
+        );
+        // Bins which do not add up to the count
+        assert_eq!(
+            with(count, &5.0f64.to_le_bytes()),
+            Err(SketchError::InvalidEncoding)
+        );
+        assert_eq!(
+            with(bin_count, &2.0f64.to_le_bytes()),
+            Err(SketchError::InvalidEncoding)
+        );
+
+        // A bin index repeated within a store
+        let mut sketch = DDSketch::new(0.01);
+        sketch.add(2.0);
+        sketch.add(200.0);
+        let mut buf = sketch.encode().to_vec();
+        let bins = 1 + 8 * 6 + 4;
+        let first_index = buf[bins..bins + 4].to_vec();
+        buf[bins + 12..bins + 16].copy_from_slice(&first_index);
+        assert_eq!(DDSketch::decode(&buf), Err(SketchError::InvalidEncoding));
+
+        // Merged sketches still decode despite rounding in their counts
+        let mut merged = DDSketch::new(0.01);
+        for v in 1..=100 {
+            let mut other = DDSketch::new(0.01);
+            other.add_weighted(f64::from(v), 0.1);
+            merged.merge(&other).unwrap();
+        }
+        assert_eq!(DDSketch::decode(&merged.encode()), Ok(merged));
+    }
+}
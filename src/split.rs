@@ -0,0 +1,252 @@
+use bytes::Bytes;
+use memchr::memchr;
+use std::fmt;
+
+use crate::message::Message;
+use crate::pdu::{ParseError, ParserConfig, PDU};
+
+/// An iterator over the lines of a datagram or stream buffer. Lines are
+/// separated by `\n` or `\r\n`, trailing NUL padding is dropped and empty
+/// lines are skipped. Each line shares the allocation of the original buffer.
+#[derive(Debug, Clone)]
+pub struct Lines {
+    remaining: Bytes,
+}
+
+impl Lines {
+    pub fn new(buf: Bytes) -> Self {
+        let end = buf.iter().rposition(|b| *b != 0).map_or(0, |last| last + 1);
+        Lines {
+            remaining: buf.slice(..end),
+        }
+    }
+}
+
+impl Iterator for Lines {
+    type Item = Bytes;
+
+    fn next(&mut self) -> Option<Bytes> {
+        while !self.remaining.is_empty() {
+            let mut line = match memchr(b'\n', &self.remaining) {
+                Some(index) => {
+                    let line = self.remaining.split_to(index + 1);
+                    line.slice(..index)
+                }
+                None => self.remaining.split_off(0),
+            };
+            if line.last() == Some(&b'\r') {
+                line.truncate(line.len() - 1);
+            }
+            if !line.is_empty() {
+                return Some(line);
+            }
+        }
+        None
+    }
+}
+
+/// A line which could not be parsed, along with the reason.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineError {
+    line: Bytes,
+    error: ParseError,
+}
+
+impl LineError {
+    pub fn new(line: Bytes, error: ParseError) -> Self {
+        LineError { line, error }
+    }
+
+    /// The raw line which was rejected.
+    pub fn line(&self) -> &Bytes {
+        &self.line
+    }
+
+    pub fn error(&self) -> &ParseError {
+        &self.error
+    }
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} in line {:?}",
+            self.error,
+            String::from_utf8_lossy(&self.line)
+        )
+    }
+}
+
+impl std::error::Error for LineError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// An iterator of PDUs parsed from each line of a buffer. A line which fails
+/// to parse yields a `LineError` and does not stop the iteration.
+#[derive(Debug, Clone)]
+pub struct Pdus {
+    lines: Lines,
+    config: ParserConfig,
+}
+
+impl Pdus {
+    pub fn new(buf: Bytes) -> Self {
+        Self::with_config(buf, ParserConfig::default())
+    }
+
+    /// Parse each line of the buffer with the given configuration.
+    pub fn with_config(buf: Bytes, config: ParserConfig) -> Self {
+        Pdus {
+            lines: Lines::new(buf),
+            config,
+        }
+    }
+}
+
+impl Iterator for Pdus {
+    type Item = Result<PDU, LineError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = self.lines.next()?;
+        Some(
+            PDU::parse_with(&self.config, line.clone())
+                .map_err(|error| LineError::new(line, error)),
+        )
+    }
+}
+
+/// An iterator of messages, metrics or events, parsed from each line of a
+/// buffer. A line which fails to parse yields a `LineError` and does not stop
+/// the iteration.
+#[derive(Debug, Clone)]
+pub struct Messages {
+    lines: Lines,
+    config: ParserConfig,
+}
+
+impl Messages {
+    pub fn new(buf: Bytes) -> Self {
+        Self::with_config(buf, ParserConfig::default())
+    }
+
+    /// Parse each metric line of the buffer with the given configuration.
+    pub fn with_config(buf: Bytes, config: ParserConfig) -> Self {
+        Messages {
+            lines: Lines::new(buf),
+            config,
+        }
+    }
+}
+
+impl Iterator for Messages {
+    type Item = Result<Message, LineError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = self.lines.next()?;
+        Some(
+            Message::parse_with(&self.config, line.clone())
+                .map_err(|error| LineError::new(line, error)),
+        )
+    }
+}
+
+/// Split a datagram or buffer into PDUs, collecting the lines which failed to
+/// parse separately rather than aborting on the first bad line.
+pub fn split_datagram(buf: Bytes) -> (Vec<PDU>, Vec<LineError>) {
+    let mut pdus = Vec::new();
+    let mut errors = Vec::new();
+    for result in Pdus::new(buf) {
+        match result {
+            Ok(pdu) => pdus.push(pdu),
+            Err(e) => errors.push(e),
+        }
+    }
+    (pdus, errors)
+}
+
+#[cfg(test)]
+pub mod atest {
+    use super::*;
+    use crate::pdu::{NameTagStyle, ParseErrorKind};
+
+    #[test]
+    fn split_lines() {
+        let buf = Bytes::from_static(b"a:1|c\r\n\nb:2|g\n\r\nc:3|ms\0\0\0");
+        let lines: Vec<Bytes> = Lines::new(buf).collect();
+        assert_eq!(
+            lines,
+            vec![
+                Bytes::from_static(b"a:1|c"),
+                Bytes::from_static(b"b:2|g"),
+                Bytes::from_static(b"c:3|ms"),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_empty() {
+        assert_eq!(Lines::new(Bytes::from_static(b"")).count(), 0);
+        assert_eq!(Lines::new(Bytes::from_static(b"\n\r\n\0")).count(), 0);
+    }
+
+    #[test]
+    fn split_shares_allocation() {
+        let buf = Bytes::from(b"foo.bar:3|c\nbaz:1|g\n".to_vec());
+        let range = buf.as_ptr() as usize..buf.as_ptr() as usize + buf.len();
+        for pdu in Pdus::new(buf.clone()) {
+            let pdu = pdu.unwrap();
+            assert!(range.contains(&(pdu.name().as_ptr() as usize)));
+        }
+    }
+
+    #[test]
+    fn split_collects_errors() {
+        let (pdus, errors) =
+            split_datagram(Bytes::from_static(b"a:1|c\ngarbage\nb:2|c|xy\nc:3|g\n"));
+        assert_eq!(pdus.len(), 2);
+        assert_eq!(pdus[0].name(), b"a");
+        assert_eq!(pdus[1].name(), b"c");
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].line().as_ref(), b"garbage");
+        assert_eq!(
+            errors[0].error().kind(),
+            ParseErrorKind::MissingTypeSeparator
+        );
+        assert_eq!(errors[1].line().as_ref(), b"b:2|c|xy");
+    }
+
+    #[test]
+    fn split_with_config() {
+        let buf = Bytes::from_static(b"a:1|c|\nb;env=prod:2|c\n");
+        let config = ParserConfig::strict().with_name_tags(NameTagStyle::Graphite);
+        let results: Vec<Result<PDU, LineError>> = Pdus::with_config(buf, config).collect();
+        assert_eq!(
+            results[0].as_ref().unwrap_err().error().kind(),
+            ParseErrorKind::EmptyField
+        );
+        let pdu = results[1].as_ref().unwrap();
+        assert_eq!(pdu.name(), b"b");
+        assert_eq!(pdu.name_tags().unwrap(), b"env=prod");
+    }
+
+    #[test]
+    fn split_messages() {
+        let buf = Bytes::from_static(
+            b"a:1|c
+_e{1,1}:t|x
+_e{9,1}:t|x
+",
+        );
+        let messages: Vec<Result<Message, LineError>> = Messages::new(buf).collect();
+        assert_eq!(messages.len(), 3);
+        assert!(matches!(messages[0], Ok(Message::Metric(_))));
+        assert!(matches!(messages[1], Ok(Message::Event(_))));
+        assert_eq!(
+            messages[2].as_ref().unwrap_err().error().kind(),
+            ParseErrorKind::LengthMismatch
+        );
+    }
+}
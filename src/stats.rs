@@ -0,0 +1,251 @@
+use std::time::Duration;
+
+/// The statistics of the samples within one percentile threshold of a timer,
+/// as produced by Etsy statsd. A positive threshold such as `90` covers the
+/// lowest 90% of samples, while a negative one such as `-10` covers the
+/// highest 10%.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PercentileStats {
+    pub threshold: f64,
+    /// The number of samples within the threshold.
+    pub count: usize,
+    pub mean: f64,
+    /// The largest sample within a positive threshold, or the smallest within
+    /// a negative one.
+    pub bound: f64,
+    pub sum: f64,
+    pub sum_squares: f64,
+}
+
+impl PercentileStats {
+    /// The suffix Etsy statsd gives the series of this threshold, such as
+    /// `90`, `99_9` or `top10`.
+    pub fn suffix(&self) -> String {
+        format!("{}", self.threshold)
+            .replace('.', "_")
+            .replace('-', "top")
+    }
+}
+
+/// The derived series of a timer over one flush interval, computed exactly as
+/// Etsy statsd does so aggregated numbers can be compared like for like.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimerStats {
+    /// The number of samples sent by clients, accounting for sampling.
+    pub count: f64,
+    /// The count per second over the flush interval.
+    pub count_ps: f64,
+    pub sum: f64,
+    pub sum_squares: f64,
+    pub mean: f64,
+    pub median: f64,
+    /// The population standard deviation of the samples.
+    pub std: f64,
+    pub upper: f64,
+    pub lower: f64,
+    pub percentiles: Vec<PercentileStats>,
+}
+
+impl TimerStats {
+    /// Compute the statistics of a timer from its samples, the upscaled count
+    /// of samples and the percentile thresholds to report. Returns `None`
+    /// when there are no samples.
+    pub fn from_samples(
+        samples: &[f64],
+        count: f64,
+        thresholds: &[f64],
+        flush_interval: Duration,
+    ) -> Option<Self> {
+        let mut values = samples.to_vec();
+        values.sort_by(f64::total_cmp);
+        let n = values.len();
+        let (lower, upper) = (*values.first()?, values[n - 1]);
+
+        let mut cumulative = Vec::with_capacity(n);
+        let mut cumulative_squares = Vec::with_capacity(n);
+        let (mut sum, mut sum_squares) = (0.0, 0.0);
+        for value in &values {
+            sum += value;
+            sum_squares += value * value;
+            cumulative.push(sum);
+            cumulative_squares.push(sum_squares);
+        }
+
+        let mut percentiles = Vec::with_capacity(thresholds.len());
+        for &threshold in thresholds {
+            // A single sample is within every threshold
+            let mut stats = PercentileStats {
+                threshold,
+                count: n,
+                mean: lower,
+                bound: upper,
+                sum: lower,
+                sum_squares: lower * lower,
+            };
+            if n > 1 {
+                let within = (threshold.abs() / 100.0 * n as f64).round() as usize;
+                let within = within.min(n);
+                if within == 0 {
+                    continue;
+                }
+                if threshold > 0.0 {
+                    stats.bound = values[within - 1];
+                    stats.sum = cumulative[within - 1];
+                    stats.sum_squares = cumulative_squares[within - 1];
+                } else {
+                    // Etsy statsd subtracts a cumulative sum it reads from
+                    // before the first sample when a negative threshold
+                    // covers every sample, so its sum, sum of squares and
+                    // mean are NaN. Reproduce that for compatibility.
+                    let (below, below_squares) = match n - within {
+                        0 => (f64::NAN, f64::NAN),
+                        first => (cumulative[first - 1], cumulative_squares[first - 1]),
+                    };
+                    stats.bound = values[n - within];
+                    stats.sum = sum - below;
+                    stats.sum_squares = sum_squares - below_squares;
+                }
+                stats.count = within;
+                stats.mean = stats.sum / within as f64;
+            }
+            percentiles.push(stats);
+        }
+
+        let mean = sum / n as f64;
+        let sum_of_diffs: f64 = values.iter().map(|v| (v - mean) * (v - mean)).sum();
+        let mid = n / 2;
+        let median = if n % 2 == 1 {
+            values[mid]
+        } else {
+            (values[mid - 1] + values[mid]) / 2.0
+        };
+
+        Some(TimerStats {
+            count,
+            count_ps: count / flush_interval.as_secs_f64(),
+            sum,
+            sum_squares,
+            mean,
+            median,
+            std: (sum_of_diffs / n as f64).sqrt(),
+            upper,
+            lower,
+            percentiles,
+        })
+    }
+
+    /// The derived series named and ordered as Etsy statsd emits them, such
+    /// as `count_90`, `mean_90`, `upper_90`, ..., `mean` and `median`.
+    pub fn series(&self) -> Vec<(String, f64)> {
+        let mut series = Vec::with_capacity(9 + self.percentiles.len() * 5);
+        for stats in &self.percentiles {
+            let suffix = stats.suffix();
+            let bound = if stats.threshold > 0.0 {
+                "upper"
+            } else {
+                "lower"
+            };
+            series.push((format!("count_{}", suffix), stats.count as f64));
+            series.push((format!("mean_{}", suffix), stats.mean));
+            series.push((format!("{}_{}", bound, suffix), stats.bound));
+            series.push((format!("sum_{}", suffix), stats.sum));
+            series.push((format!("sum_squares_{}", suffix), stats.sum_squares));
+        }
+        let overall = [
+            ("std", self.std),
+            ("upper", self.upper),
+            ("lower", self.lower),
+            ("count", self.count),
+            ("count_ps", self.count_ps),
+            ("sum", self.sum),
+            ("sum_squares", self.sum_squares),
+            ("mean", self.mean),
+            ("median", self.median),
+        ];
+        series.extend(overall.iter().map(|(name, v)| (name.to_string(), *v)));
+        series
+    }
+}
+
+#[cfg(test)]
+pub mod atest {
+    use super::*;
+
+    #[test]
+    fn timer_stats() {
+        let samples = [7.0, 1.0, 10.0, 3.0, 2.0, 4.0, 6.0, 5.0, 9.0, 8.0];
+        let stats =
+            TimerStats::from_samples(&samples, 10.0, &[90.0, -10.0], Duration::from_secs(10))
+                .unwrap();
+        assert_eq!(stats.count, 10.0);
+        assert_eq!(stats.count_ps, 1.0);
+        assert_eq!(stats.sum, 55.0);
+        assert_eq!(stats.sum_squares, 385.0);
+        assert_eq!(stats.mean, 5.5);
+        assert_eq!(stats.median, 5.5);
+        assert_eq!(stats.std, 8.25f64.sqrt());
+        assert_eq!((stats.lower, stats.upper), (1.0, 10.0));
+
+        let upper_90 = &stats.percentiles[0];
+        assert_eq!(upper_90.suffix(), "90");
+        assert_eq!(upper_90.count, 9);
+        assert_eq!(upper_90.bound, 9.0);
+        assert_eq!(upper_90.sum, 45.0);
+        assert_eq!(upper_90.sum_squares, 285.0);
+        assert_eq!(upper_90.mean, 5.0);
+
+        let top_10 = &stats.percentiles[1];
+        assert_eq!(top_10.suffix(), "top10");
+        assert_eq!(top_10.count, 1);
+        assert_eq!(top_10.bound, 10.0);
+        assert_eq!(top_10.sum, 10.0);
+        assert_eq!(top_10.sum_squares, 100.0);
+
+        let series = stats.series();
+        assert_eq!(series.len(), 19);
+        assert_eq!(series[0], ("count_90".to_string(), 9.0));
+        assert_eq!(series[2], ("upper_90".to_string(), 9.0));
+        assert_eq!(series[7], ("lower_top10".to_string(), 10.0));
+        assert_eq!(series[18], ("median".to_string(), 5.5));
+    }
+
+    #[test]
+    fn single_sample_timer_stats() {
+        let stats =
+            TimerStats::from_samples(&[100.0], 1.0, &[90.0], Duration::from_secs(1)).unwrap();
+        assert_eq!(stats.std, 0.0);
+        assert_eq!(stats.median, 100.0);
+        assert_eq!(
+            stats.percentiles,
+            vec![PercentileStats {
+                threshold: 90.0,
+                count: 1,
+                mean: 100.0,
+                bound: 100.0,
+                sum: 100.0,
+                sum_squares: 10000.0,
+            }]
+        );
+    }
+
+    #[test]
+    fn edge_threshold_timer_stats() {
+        let samples = [1.0, 2.0, 3.0, 4.0];
+        let stats =
+            TimerStats::from_samples(&samples, 8.0, &[10.0, 99.9, -100.0], Duration::from_secs(2))
+                .unwrap();
+        // Thresholds covering no samples are skipped
+        assert_eq!(stats.percentiles.len(), 2);
+        assert_eq!(stats.percentiles[0].suffix(), "99_9");
+        assert_eq!(stats.percentiles[0].count, 4);
+        // As in Etsy statsd, a negative threshold covering every sample has
+        // no sum
+        let all = &stats.percentiles[1];
+        assert_eq!(all.count, 4);
+        assert_eq!(all.bound, 1.0);
+        assert!(all.sum.is_nan() && all.sum_squares.is_nan() && all.mean.is_nan());
+        assert_eq!(stats.count_ps, 4.0);
+        assert_eq!(stats.median, 2.5);
+        assert!(TimerStats::from_samples(&[], 0.0, &[90.0], Duration::from_secs(1)).is_none());
+    }
+}
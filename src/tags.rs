@@ -0,0 +1,165 @@
+use memchr::memchr;
+
+/// A single tag borrowed from a tag section. Bare tags such as `#prod` have no
+/// value, while `#env:prod` splits on the first `:` so values may themselves
+/// contain `:`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Tag<'a> {
+    pub key: &'a [u8],
+    pub value: Option<&'a [u8]>,
+}
+
+impl<'a> Tag<'a> {
+    pub fn new(key: &'a [u8], value: Option<&'a [u8]>) -> Self {
+        Tag { key, value }
+    }
+
+    /// Split a single raw tag entry into its key and optional value.
+    pub fn parse(entry: &'a [u8]) -> Self {
+        Self::split(entry, b':')
+    }
+
+    /// Whether the tag parses back the same once written in DogStatsD
+    /// `key:value` form: the key may not contain `:` or `,`, nor the value
+    /// `,`. Tags from other formats, such as name tags, may not.
+    pub(crate) fn is_dogstatsd(&self) -> bool {
+        !self.key.iter().any(|b| *b == b':' || *b == b',')
+            && !matches!(self.value, Some(value) if value.contains(&b','))
+    }
+
+    fn split(entry: &'a [u8], assignment: u8) -> Self {
+        match memchr(assignment, entry) {
+            Some(index) => Tag::new(&entry[..index], Some(&entry[index + 1..])),
+            None => Tag::new(entry, None),
+        }
+    }
+}
+
+/// A zero-copy iterator over the tags of a `#a:b,c:d,e` style tag section.
+/// Empty entries, such as those produced by stray commas, are skipped.
+///
+/// Tags embedded in a metric name, such as Graphite's `;env=prod`, are
+/// yielded first when iterating the tags of a PDU.
+#[derive(Debug, Clone)]
+pub struct Tags<'a> {
+    remaining: &'a [u8],
+    separator: u8,
+    assignment: u8,
+    // A DogStatsD tag section to continue with once `remaining` is exhausted
+    then: &'a [u8],
+}
+
+impl<'a> Tags<'a> {
+    /// Iterate the tags of a tag section, without the leading `#`.
+    pub fn new(section: &'a [u8]) -> Self {
+        Tags {
+            remaining: section,
+            separator: b',',
+            assignment: b':',
+            then: &[],
+        }
+    }
+
+    /// An iterator which yields no tags.
+    pub fn empty() -> Self {
+        Self::new(&[])
+    }
+
+    /// Iterate a tag section using the given entry separator and key/value
+    /// assignment bytes, followed by a DogStatsD tag section.
+    pub(crate) fn with_format(
+        section: &'a [u8],
+        separator: u8,
+        assignment: u8,
+        then: &'a [u8],
+    ) -> Self {
+        Tags {
+            remaining: section,
+            separator,
+            assignment,
+            then,
+        }
+    }
+}
+
+impl<'a> Iterator for Tags<'a> {
+    type Item = Tag<'a>;
+
+    fn next(&mut self) -> Option<Tag<'a>> {
+        loop {
+            if self.remaining.is_empty() {
+                if self.then.is_empty() {
+                    return None;
+                }
+                *self = Tags::new(self.then);
+            }
+            let entry = match memchr(self.separator, self.remaining) {
+                Some(index) => {
+                    let entry = &self.remaining[..index];
+                    self.remaining = &self.remaining[index + 1..];
+                    entry
+                }
+                None => std::mem::take(&mut self.remaining),
+            };
+            if !entry.is_empty() {
+                return Some(Tag::split(entry, self.assignment));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod atest {
+    use super::*;
+
+    #[test]
+    fn split_tags() {
+        let tags: Vec<Tag> = Tags::new(b"a:b,c:d,e").collect();
+        assert_eq!(
+            tags,
+            vec![
+                Tag::new(b"a", Some(b"b")),
+                Tag::new(b"c", Some(b"d")),
+                Tag::new(b"e", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn tag_values_with_colons() {
+        let tags: Vec<Tag> = Tags::new(b"url:http://host:80/,empty:").collect();
+        assert_eq!(tags[0], Tag::new(b"url", Some(b"http://host:80/")));
+        assert_eq!(tags[1], Tag::new(b"empty", Some(b"")));
+    }
+
+    #[test]
+    fn stray_commas() {
+        let tags: Vec<Tag> = Tags::new(b",a,,b:1,").collect();
+        assert_eq!(tags, vec![Tag::new(b"a", None), Tag::new(b"b", Some(b"1"))]);
+        assert_eq!(Tags::new(b",,").count(), 0);
+        assert_eq!(Tags::empty().count(), 0);
+    }
+
+    #[test]
+    fn chained_tags() {
+        let tags: Vec<Tag> = Tags::with_format(b"env=prod;;bare", b';', b'=', b"a:b").collect();
+        assert_eq!(
+            tags,
+            vec![
+                Tag::new(b"env", Some(b"prod")),
+                Tag::new(b"bare", None),
+                Tag::new(b"a", Some(b"b")),
+            ]
+        );
+        assert_eq!(Tags::with_format(b"", b';', b'=', b"a").count(), 1);
+    }
+
+    #[test]
+    fn dogstatsd_tags() {
+        assert!(Tag::new(b"a", Some(b"b:c")).is_dogstatsd());
+        assert!(Tag::new(b"a", None).is_dogstatsd());
+        assert!(!Tag::new(b"a:b", Some(b"c")).is_dogstatsd());
+        assert!(!Tag::new(b"a,b", None).is_dogstatsd());
+        assert!(!Tag::new(b"a", Some(b"b,c")).is_dogstatsd());
+    }
+}
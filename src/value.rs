@@ -0,0 +1,172 @@
+use crate::metric::{MetricError, MetricType};
+
+/// A value field interpreted according to the type of its metric.
+///
+/// Following Etsy statsd, a gauge value with a leading `+` or `-` is a change
+/// to the current value of the gauge, while a bare number replaces it. For
+/// every other numeric type a leading sign is simply the sign of the number.
+/// Non-finite values such as `NaN` and `inf` are rejected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParsedValue<'a> {
+    /// A plain integer, parsed without going through float parsing.
+    Integer(i64),
+    /// Any other number, such as `1.5` or `1e3`.
+    Absolute(f64),
+    /// A signed change to the current value of a gauge.
+    Delta(f64),
+    /// The raw value of a set, or of a metric of unknown type.
+    String(&'a [u8]),
+}
+
+impl<'a> ParsedValue<'a> {
+    /// Parse a value field for a metric of the given type.
+    pub fn parse(value: &'a [u8], metric_type: MetricType) -> Result<Self, MetricError> {
+        if let MetricType::Set | MetricType::Unknown = metric_type {
+            return Ok(ParsedValue::String(value));
+        }
+        let (negative, magnitude) = match value {
+            [b'+', rest @ ..] => (false, rest),
+            [b'-', rest @ ..] => (true, rest),
+            _ => (false, value),
+        };
+        // Rejects another sign as well as the names of non-finite values
+        if !matches!(magnitude.first(), Some(b'0'..=b'9') | Some(b'.')) {
+            return Err(MetricError::InvalidValue);
+        }
+
+        if metric_type == MetricType::Gauge && magnitude.len() < value.len() {
+            let delta = parse_float(magnitude)?;
+            return Ok(ParsedValue::Delta(if negative { -delta } else { delta }));
+        }
+        if let Some(integer) = parse_integer(magnitude) {
+            let integer = if negative { -integer } else { integer };
+            return Ok(ParsedValue::Integer(integer));
+        }
+        let number = parse_float(magnitude)?;
+        let number = if negative { -number } else { number };
+        Ok(ParsedValue::Absolute(number))
+    }
+
+    /// The numeric value, or the change for a delta, for every value except
+    /// strings.
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            ParsedValue::Integer(v) => Some(v as f64),
+            ParsedValue::Absolute(v) | ParsedValue::Delta(v) => Some(v),
+            ParsedValue::String(_) => None,
+        }
+    }
+
+    pub fn is_delta(&self) -> bool {
+        matches!(self, ParsedValue::Delta(_))
+    }
+}
+
+/// Parse an unsigned integer of up to 18 digits, which always fits an `i64`.
+/// Longer integers are left to float parsing.
+fn parse_integer(digits: &[u8]) -> Option<i64> {
+    if digits.is_empty() || digits.len() > 18 || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    Some(
+        digits
+            .iter()
+            .fold(0, |number, digit| number * 10 + i64::from(digit - b'0')),
+    )
+}
+
+fn parse_float(buf: &[u8]) -> Result<f64, MetricError> {
+    std::str::from_utf8(buf)
+        .ok()
+        .and_then(|number| number.parse::<f64>().ok())
+        .filter(|number| number.is_finite())
+        .ok_or(MetricError::InvalidValue)
+}
+
+#[cfg(test)]
+pub mod atest {
+    use super::*;
+
+    fn parse(value: &'static [u8], metric_type: MetricType) -> ParsedValue<'static> {
+        ParsedValue::parse(value, metric_type).unwrap()
+    }
+
+    #[test]
+    fn numeric_values() {
+        assert_eq!(parse(b"42", MetricType::Counter), ParsedValue::Integer(42));
+        assert_eq!(parse(b"+42", MetricType::Counter), ParsedValue::Integer(42));
+        assert_eq!(parse(b"-7", MetricType::Counter), ParsedValue::Integer(-7));
+        assert_eq!(parse(b"1.5", MetricType::Timer), ParsedValue::Absolute(1.5));
+        assert_eq!(parse(b".5", MetricType::Timer), ParsedValue::Absolute(0.5));
+        assert_eq!(
+            parse(b"1e3", MetricType::Counter),
+            ParsedValue::Absolute(1000.0)
+        );
+        assert_eq!(
+            parse(b"-2.5E-1", MetricType::Distribution),
+            ParsedValue::Absolute(-0.25)
+        );
+        // Integers too long for the fast path fall back to float parsing
+        assert_eq!(
+            parse(b"12345678901234567890", MetricType::Counter),
+            ParsedValue::Absolute(12345678901234567890.0)
+        );
+        assert_eq!(parse(b"-3", MetricType::Counter).as_f64(), Some(-3.0));
+    }
+
+    #[test]
+    fn gauge_values() {
+        assert_eq!(parse(b"5", MetricType::Gauge), ParsedValue::Integer(5));
+        assert_eq!(parse(b"+5", MetricType::Gauge), ParsedValue::Delta(5.0));
+        assert_eq!(parse(b"-5", MetricType::Gauge), ParsedValue::Delta(-5.0));
+        assert_eq!(parse(b"-0.5", MetricType::Gauge), ParsedValue::Delta(-0.5));
+        assert!(parse(b"+1e3", MetricType::Gauge).is_delta());
+        assert!(!parse(b"1e3", MetricType::Gauge).is_delta());
+    }
+
+    #[test]
+    fn string_values() {
+        assert_eq!(
+            parse(b"user1", MetricType::Set),
+            ParsedValue::String(b"user1")
+        );
+        assert_eq!(parse(b"+5", MetricType::Set), ParsedValue::String(b"+5"));
+        assert_eq!(
+            parse(b"xyz", MetricType::Unknown),
+            ParsedValue::String(b"xyz")
+        );
+        assert_eq!(parse(b"xyz", MetricType::Unknown).as_f64(), None);
+    }
+
+    #[test]
+    fn invalid_values() {
+        let invalid: Vec<&'static [u8]> = vec![
+            b"",
+            b"+",
+            b"-",
+            b"+-5",
+            b"--5",
+            b"NaN",
+            b"nan",
+            b"inf",
+            b"-inf",
+            b"+infinity",
+            b"1e400",
+            b" 5",
+            b"5 ",
+            b"0x10",
+            b"1,5",
+            b"abc",
+        ];
+        for value in invalid {
+            for metric_type in [MetricType::Counter, MetricType::Gauge] {
+                assert_eq!(
+                    ParsedValue::parse(value, metric_type),
+                    Err(MetricError::InvalidValue),
+                    "{}",
+                    String::from_utf8_lossy(value)
+                );
+            }
+        }
+    }
+}